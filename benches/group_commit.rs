@@ -0,0 +1,51 @@
+//! Compares insert throughput with and without group commit batching in the `DbWriter`.
+//!
+//! Run with `cargo bench --bench group_commit -- [rows]`.
+
+use std::time::{Duration, Instant};
+use rusq::Context;
+use rusq::common::Value;
+use rusq::options::ContextOptions;
+use rusq::queries::{Create, Insert};
+
+fn main() {
+    let rows: usize = std::env::args().skip(1).find_map(|a| a.parse().ok()).unwrap_or(5_000);
+
+    let autocommit = run("autocommit", rows, ContextOptions::create());
+    let batched = run("batched", rows, ContextOptions::create().batch(500, Duration::from_millis(5)));
+
+    println!("Speed up: {:.1}x", autocommit.as_secs_f64() / batched.as_secs_f64());
+}
+
+fn run(name: &str, rows: usize, options: ContextOptions) -> Duration {
+    let path = std::env::temp_dir().join(format!("rusq_group_commit_{}.db", name));
+    let _ = std::fs::remove_file(&path);
+
+    let context = Context::create_with_options(path.to_string_lossy().into_owned(), options).unwrap();
+    let writer = context.get_writer().unwrap();
+
+    writer.execute_query(Create::create("CREATE TABLE telemetry (id INTEGER PRIMARY KEY, sensor TEXT, reading REAL)").unwrap()).unwrap();
+
+    let start = Instant::now();
+
+    for i in 0..rows {
+        let query = Insert::create("telemetry", vec![
+            Value::create("sensor", format!("sensor_{}", i % 10)),
+            Value::create("reading", i as f64),
+        ]).unwrap();
+
+        writer.post_query(query).unwrap();
+    }
+
+    // Requests are handled in order, so once this returns every insert has been committed.
+    writer.execute_query(Insert::create("telemetry", vec![Value::create("sensor", "done")]).unwrap()).unwrap();
+
+    let elapsed = start.elapsed();
+
+    println!("{}: {} rows in {:.3}s ({:.0} rows/s)", name, rows, elapsed.as_secs_f64(), rows as f64 / elapsed.as_secs_f64());
+
+    drop(context);
+    let _ = std::fs::remove_file(&path);
+
+    elapsed
+}
@@ -0,0 +1,154 @@
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+use crate::common::{BlobRef, BlobValue, Query, Queryable};
+use crate::error::Error;
+use crate::queries::{execute_sql, with_savepoint};
+
+/// The table blobs are kept in, keyed by the hex encoded SHA-256 of their data.
+pub const BLOB_TABLE: &str = "rusq_blobs";
+
+const CREATE_SQL: &str = "CREATE TABLE IF NOT EXISTS rusq_blobs (hash TEXT NOT NULL PRIMARY KEY, size INTEGER NOT NULL, ref_count INTEGER NOT NULL, data BLOB NOT NULL);";
+
+/// Add a reference to a blob in the store, only writing the data if it isn't already stored.
+pub struct StoreBlob {
+    sql: String,
+    hash: String,
+    blob: BlobValue,
+}
+
+/// Remove a reference to a stored blob. The data is kept until `CollectBlobGarbage` is run.
+pub struct ReleaseBlob {
+    sql: String,
+    hash: String,
+}
+
+/// Delete every stored blob that is no longer referenced.
+pub struct CollectBlobGarbage {
+    sql: String,
+}
+
+impl StoreBlob {
+    /// Returns the query and the hash to store in place of the blob, i.e. in a `TEXT` column of the user table.
+    /// File data is read once here to compute the hash, then streamed again when the query is executed.
+    /// The data is hashed again as it's written, and the query fails if the file changed in between.
+    pub fn create(blob: BlobRef) -> Result<(Query, String), Error> {
+        let blob = BlobRef::get(BLOB_TABLE, "data", blob)?;
+        let hash = blob.compute_checksum()?;
+
+        let sql = format!("UPDATE {} SET ref_count = ref_count + 1 WHERE hash = ?1;", BLOB_TABLE);
+
+        let query: Query = Box::new(StoreBlob {
+            sql,
+            hash: hash.clone(),
+            blob,
+        });
+
+        Ok((query, hash))
+    }
+
+    fn handle_execute(&self, connection: &Connection) -> Result<usize, Error> {
+        ensure_table(connection)?;
+
+        // Already stored, so only the reference count changes.
+        if execute_sql(connection, &self.sql, &[&self.hash])? > 0 {
+            return Ok(1);
+        }
+
+        let sql = format!("INSERT INTO {} (hash, size, ref_count, data) VALUES (?1, ?2, 1, ZEROBLOB(?2));", BLOB_TABLE);
+        let rows = execute_sql(connection, &sql, rusqlite::params![self.hash, self.blob.get_size() as i64])?;
+
+        let row_id = connection.last_insert_rowid();
+        let written = self.blob.write_hashed(connection, row_id)?;
+
+        // The savepoint is rolled back, so a changed file isn't stored under the wrong hash.
+        if written != self.hash {
+            return Err(Error::ChecksumMismatch {
+                table: String::from(BLOB_TABLE),
+                field: String::from("data"),
+                row_id,
+                expected: Some(self.hash.clone()),
+                actual: written,
+            });
+        }
+
+        Ok(rows)
+    }
+}
+
+impl ReleaseBlob {
+    pub fn create<T>(hash: T) -> Result<Query, Error> where T : Into<String> {
+        let sql = format!("UPDATE {} SET ref_count = ref_count - 1 WHERE hash = ?1 AND ref_count > 0;", BLOB_TABLE);
+
+        Ok(Box::new(ReleaseBlob {
+            sql,
+            hash: hash.into(),
+        }))
+    }
+}
+
+impl CollectBlobGarbage {
+    pub fn create() -> Result<Query, Error> {
+        Ok(Box::new(CollectBlobGarbage {
+            sql: format!("DELETE FROM {} WHERE ref_count <= 0;", BLOB_TABLE)
+        }))
+    }
+}
+
+impl Queryable for StoreBlob {
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        with_savepoint(connection, "rusq_store_blob", || self.handle_execute(connection))
+    }
+
+    fn get_type_name(&self) -> &'static str {
+        "STORE_BLOB"
+    }
+
+    fn get_raw_sql(&self) -> &str {
+        self.sql.as_str()
+    }
+}
+
+impl Queryable for ReleaseBlob {
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        ensure_table(connection)?;
+        execute_sql(connection, &self.sql, &[&self.hash])
+    }
+
+    fn get_type_name(&self) -> &'static str {
+        "RELEASE_BLOB"
+    }
+
+    fn get_raw_sql(&self) -> &str {
+        self.sql.as_str()
+    }
+}
+
+impl Queryable for CollectBlobGarbage {
+    /// Returns the number of blobs deleted.
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        ensure_table(connection)?;
+        execute_sql(connection, &self.sql, NO_PARAMS)
+    }
+
+    fn get_type_name(&self) -> &'static str {
+        "COLLECT_BLOB_GARBAGE"
+    }
+
+    fn get_raw_sql(&self) -> &str {
+        self.sql.as_str()
+    }
+}
+
+/// Find the rowid of a stored blob, so it can be opened for reading.
+pub(crate) fn get_row_id(connection: &Connection, hash: &str) -> Result<i64, Error> {
+    let sql = format!("SELECT rowid FROM {} WHERE hash = ?1;", BLOB_TABLE);
+
+    let row_id = connection.query_row(&sql, &[hash], |row| row.get(0))
+        .optional()
+        .map_err(|e| Error::from_execute(&sql, e))?;
+
+    row_id.ok_or(Error::UnexpectedRowCount { sql, matched: 0 })
+}
+
+fn ensure_table(connection: &Connection) -> Result<(), Error> {
+    connection.execute_batch(CREATE_SQL).map_err(|e| Error::from_execute(CREATE_SQL, e))
+}
@@ -0,0 +1,159 @@
+use std::io::{self, Write};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+use crate::error::Error;
+use crate::identifier::Identifier;
+
+/// The sidecar table used by `ChecksumStorage::Sidecar`.
+pub const CHECKSUM_TABLE: &str = "rusq_blob_checksums";
+
+/// Where the SHA-256 checksum of a blob is stored, as a hex string.
+/// A blob written without a checksum leaves the stored one as it is,
+/// use `Value::create_blob_clearing_checksum` to rewrite it and remove the old checksum.
+#[derive(Debug, Clone)]
+pub enum ChecksumStorage {
+    /// A `TEXT` column in the same row as the blob.
+    Column(Identifier),
+    /// The `rusq_blob_checksums` table, keyed by table, field and rowid.
+    /// The table is created the first time a checksum is stored.
+    /// Entries aren't removed when the row is deleted,
+    /// delete it with the row, i.e. in the same transaction, if the rowid may be reused.
+    Sidecar,
+}
+
+/// What a blob write does with the checksum in its storage.
+#[derive(Debug, Clone)]
+pub(crate) enum ChecksumAction {
+    Store(ChecksumStorage),
+    Clear(ChecksumStorage),
+}
+
+/// A writer that hashes everything written through it.
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+/// The hex encoded SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+impl ChecksumStorage {
+    /// Store checksums in `column`, in the same row as the blob.
+    pub fn column<T>(column: T) -> ChecksumStorage where T : Into<Identifier> {
+        ChecksumStorage::Column(column.into())
+    }
+
+    pub(crate) fn store(&self, connection: &Connection, table: &Identifier, field: &Identifier, row_id: i64, checksum: &str) -> Result<(), Error> {
+        match self {
+            ChecksumStorage::Column(column) => {
+                let sql = format!("UPDATE {} SET {} = ?1 WHERE rowid = ?2;", table.get_sql()?, column.get_name_sql()?);
+                connection.execute(&sql, rusqlite::params![checksum, row_id]).map_err(|e| Error::from_execute(&sql, e))?;
+            }
+            ChecksumStorage::Sidecar => {
+                let create_sql = format!("CREATE TABLE IF NOT EXISTS {} (table_name TEXT NOT NULL, field_name TEXT NOT NULL, row_id INTEGER NOT NULL, checksum TEXT NOT NULL, PRIMARY KEY (table_name, field_name, row_id));", CHECKSUM_TABLE);
+                connection.execute(&create_sql, NO_PARAMS).map_err(|e| Error::from_execute(&create_sql, e))?;
+
+                let sql = format!("INSERT OR REPLACE INTO {} (table_name, field_name, row_id, checksum) VALUES (?1, ?2, ?3, ?4);", CHECKSUM_TABLE);
+                connection.execute(&sql, rusqlite::params![table.to_string(), field.get_name(), row_id, checksum]).map_err(|e| Error::from_execute(&sql, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the checksum of a blob written without one, so it isn't verified against the old data.
+    pub(crate) fn clear(&self, connection: &Connection, table: &Identifier, field: &Identifier, row_id: i64) -> Result<(), Error> {
+        let column = match self {
+            ChecksumStorage::Column(column) => column,
+            ChecksumStorage::Sidecar => return ChecksumStorage::clear_sidecar(connection, table, field, row_id)
+        };
+
+        let sql = format!("UPDATE {} SET {} = NULL WHERE rowid = ?1;", table.get_sql()?, column.get_name_sql()?);
+        connection.execute(&sql, rusqlite::params![row_id]).map_err(|e| Error::from_execute(&sql, e))?;
+
+        Ok(())
+    }
+
+    /// The table might not have been created yet, in which case there is nothing to remove.
+    fn clear_sidecar(connection: &Connection, table: &Identifier, field: &Identifier, row_id: i64) -> Result<(), Error> {
+        let exists_sql = "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1;";
+        let exists: i64 = connection.query_row(exists_sql, &[CHECKSUM_TABLE], |row| row.get(0))
+            .map_err(|e| Error::from_execute(exists_sql, e))?;
+
+        if exists == 0 {
+            return Ok(());
+        }
+
+        let sql = format!("DELETE FROM {} WHERE table_name = ?1 AND field_name = ?2 AND row_id = ?3;", CHECKSUM_TABLE);
+        connection.execute(&sql, rusqlite::params![table.to_string(), field.get_name(), row_id]).map_err(|e| Error::from_execute(&sql, e))?;
+
+        Ok(())
+    }
+
+    /// Load the stored checksum, `None` if there isn't one.
+    pub(crate) fn load(&self, connection: &Connection, table: &Identifier, field: &Identifier, row_id: i64) -> Result<Option<String>, Error> {
+        let result = match self {
+            ChecksumStorage::Column(column) => {
+                let sql = format!("SELECT {} FROM {} WHERE rowid = ?1;", column.get_name_sql()?, table.get_sql()?);
+                connection.query_row(&sql, rusqlite::params![row_id], |row| row.get::<_, Option<String>>(0))
+                    .optional()
+                    .map_err(|e| Error::from_execute(&sql, e))?
+            }
+            ChecksumStorage::Sidecar => {
+                let sql = format!("SELECT checksum FROM {} WHERE table_name = ?1 AND field_name = ?2 AND row_id = ?3;", CHECKSUM_TABLE);
+                connection.query_row(&sql, rusqlite::params![table.to_string(), field.get_name(), row_id], |row| row.get::<_, Option<String>>(0))
+                    .optional()
+                    .map_err(|e| Error::from_execute(&sql, e))?
+            }
+        };
+
+        Ok(result.and_then(|checksum| checksum))
+    }
+
+    /// Compare a computed checksum with the stored one.
+    pub(crate) fn verify(&self, connection: &Connection, table: &Identifier, field: &Identifier, row_id: i64, actual: String) -> Result<(), Error> {
+        let expected = self.load(connection, table, field, row_id)?;
+
+        match &expected {
+            Some(expected) if *expected == actual => Ok(()),
+            _ => Err(Error::ChecksumMismatch {
+                table: table.to_string(),
+                field: field.to_string(),
+                row_id,
+                expected,
+                actual,
+            })
+        }
+    }
+}
+
+impl<W> HashingWriter<W> where W: Write {
+    pub(crate) fn create(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex encoded SHA-256 of everything written.
+    pub(crate) fn finish(mut self) -> String {
+        self.hasher.result_str()
+    }
+}
+
+impl<W> Write for HashingWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
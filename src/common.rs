@@ -1,9 +1,25 @@
 
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::path::Path;
-use rusqlite::{Connection, ToSql};
+use std::any::Any;
+use rusqlite::{Connection, Row, ToSql, NO_PARAMS};
+use rusqlite::blob::Blob;
+use crate::error::Error;
+use crate::checksum::{self, ChecksumAction, ChecksumStorage, HashingWriter};
+use crate::identifier::Identifier;
+use crate::params::{NamedValue, Params};
+use crate::queries::Select;
 
 pub trait Queryable {
-    fn execute(&self, connection: &Connection) -> Result<(),&'static str>;
+    /// Execute the query, returning the number of rows affected.
+    fn execute(&self, connection: &Connection) -> Result<usize, Error>;
+
+    /// Execute the query, also returning the rows mapped from its `RETURNING` clause.
+    /// Queries without one return no rows.
+    fn execute_returning(&self, connection: &Connection) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        Ok((self.execute(connection)?, Vec::new()))
+    }
 
     fn get_type_name(&self) -> & 'static str;
     fn get_raw_sql(&self) -> &'_ str;
@@ -15,15 +31,36 @@ pub type Query = Box<dyn Queryable + Send>;
 
 pub type BoxedValue = Box<dyn ToSql + Send + 'static>;
 
+/// A row mapped by a `Returning` mapper, taken back out with `WriteOutcome::take_returned`.
+pub type ReturnedRow = Box<dyn Any + Send>;
+
+type ReturningMapper = Box<dyn Fn(&Row<'_>) -> Result<ReturnedRow, io::Error> + Send>;
+
+/// A `RETURNING` clause and the mapper for the rows it returns.
+pub struct Returning {
+    fields: Option<Vec<Identifier>>,
+    mapper: ReturningMapper,
+}
+
 pub struct Value {
-    pub(crate) field: String,
-    pub(crate) value: ValueType
+    pub(crate) field: Identifier,
+    pub(crate) value: ValueType,
+    pub(crate) checksum: Option<ChecksumAction>
 }
 
 pub struct BlobValue {
-    pub(crate) table: String,
-    pub(crate) field: String,
-    pub(crate) data: Vec<u8>,
+    pub(crate) table: Identifier,
+    pub(crate) field: Identifier,
+    pub(crate) data: BlobData,
+    pub(crate) checksum: Option<ChecksumAction>,
+}
+
+/// The source of a loaded blob.
+/// Files are only opened when the query is executed, then streamed into the blob,
+/// so queued queries don't hold a file handle each.
+pub(crate) enum BlobData {
+    Memory(Vec<u8>),
+    File { path: Box<Path>, size: u64 },
 }
 
 pub enum BlobRef {
@@ -36,6 +73,49 @@ pub enum ValueType {
     Blob(BlobRef)
 }
 
+/// How an `Insert` resolves a uniqueness or constraint conflict.
+pub enum OnConflict {
+    /// `INSERT OR ROLLBACK`
+    Rollback,
+    /// `INSERT OR ABORT`, SQLite's default.
+    Abort,
+    /// `INSERT OR FAIL`
+    Fail,
+    /// `INSERT OR IGNORE`
+    Ignore,
+    /// `INSERT OR REPLACE`
+    Replace,
+    /// `ON CONFLICT (target) DO NOTHING`. The target may be empty to match any conflict.
+    DoNothing { target: Vec<Identifier> },
+    /// `ON CONFLICT (target) DO UPDATE SET ...`
+    DoUpdate { target: Vec<Identifier>, updates: Vec<ConflictUpdate> },
+}
+
+/// A column set by `OnConflict::DoUpdate`.
+pub struct ConflictUpdate {
+    pub(crate) field: Identifier,
+    pub(crate) value: ConflictValue,
+}
+
+pub enum ConflictValue {
+    /// `field = excluded.field`, the value that failed to insert.
+    Excluded,
+    /// A bound parameter.
+    Value(BoxedValue),
+    /// An SQL expression, e.g. `count + excluded.count`.
+    Raw(String),
+}
+
+/// A compiled `OnConflict`.
+pub(crate) struct ConflictClause {
+    /// The start of the statement, up to the table name.
+    pub(crate) prefix: &'static str,
+    /// The upsert clause following `VALUES`, empty for the `INSERT OR ...` variants.
+    pub(crate) sql: String,
+    /// Whether conflicting rows are updated, rather than inserted or ignored.
+    pub(crate) updates: bool,
+}
+
 pub enum Criteria {
     Raw(String),
     Items(Vec<CriteriaItemType>)
@@ -44,7 +124,11 @@ pub enum Criteria {
 pub enum CriteriaItemType {
     Item(CriteriaItem),
     Raw(String),
-    Group { items: Vec<CriteriaItemType>, logic: Logic }
+    /// Raw sql with `:name` placeholders.
+    RawNamed { sql: String, values: Vec<NamedValue> },
+    Group { items: Vec<CriteriaItemType>, logic: Logic },
+    /// `EXISTS` or `NOT EXISTS` a subquery.
+    Exists { select: Box<Select>, negated: bool }
 }
 
 pub enum Logic {
@@ -53,58 +137,708 @@ pub enum Logic {
 }
 
 pub struct CriteriaItem {
-    field: String,
-    operator: String,
-    value: CriteriaItemValue
+    field: Identifier,
+    operator: Operator,
+    values: Vec<CriteriaItemValue>
+}
+
+pub enum Operator {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    Like,
+    Glob,
+    In,
+    NotIn,
+    Between,
+    IsNull,
+    IsNotNull
 }
 
 pub enum CriteriaItemValue {
     Raw(String),
-    Value(BoxedValue)
+    Value(BoxedValue),
+    /// A subquery, i.e. the single operand of `IN` or a scalar compared with `=`.
+    Select(Box<Select>)
+}
+
+/// A field waiting for an operator, created with `Criteria::field`.
+pub struct CriteriaField {
+    field: Identifier
+}
+
+/// A fluent builder for `Criteria::Items`, i.e. `Criteria::field("age").gt(30).and(Criteria::field("name").like("J%"))`.
+/// Any invalid item is reported when `build` is called.
+pub struct CriteriaBuilder {
+    item: Result<CriteriaItemType, Error>
 }
 
 impl BlobRef {
 
     /// A static method to deconstruct a blob reference and return the raw blob data.
     /// The reference is considered spent once this is called.
-    pub fn get<T>(table: T, field: T, blob_ref: BlobRef) -> Result<BlobValue, & 'static str> where T : Into<String> {
+    /// File references are checked but not opened, so a missing file is reported here.
+    pub fn get<T, U>(table: T, field: U, blob_ref: BlobRef) -> Result<BlobValue, Error> where T : Into<Identifier>, U : Into<Identifier> {
+        let table = table.into();
+        let field = field.into();
+
+        // Blobs are opened by name rather than through sql, so the names are checked here.
+        table.check()?;
+        field.check()?;
 
-        
         let data = match blob_ref {
-            BlobRef::File(path) => unimplemented!(),
-            BlobRef::Memory(data) => Ok(data)
-        }?;
-        
+            BlobRef::File(path) => {
+                let metadata = fs::metadata(&path).map_err(|e| Error::from_blob(&table, &field, e))?;
+
+                if !metadata.is_file() {
+                    return Err(Error::from_blob(&table, &field, format!("`{}` is not a file", path.display())));
+                }
+
+                BlobData::File { path, size: metadata.len() }
+            }
+            BlobRef::Memory(data) => BlobData::Memory(data)
+        };
+
         Ok(BlobValue {
-            table: table.into(),
-            field: field.into(),
-            data
+            table,
+            field,
+            data,
+            checksum: None
         })
+    }
+}
+
+impl BlobValue {
+    /// The size of the blob in bytes, used to reserve space with `ZEROBLOB`.
+    pub fn get_size(&self) -> u64 {
+        match &self.data {
+            BlobData::Memory(data) => data.len() as u64,
+            BlobData::File { size, .. } => *size
+        }
+    }
+
+    /// Write the blob to `row_id`, which must already have space reserved with `ZEROBLOB`.
+    /// File data is streamed in chunks rather than loaded into memory.
+    /// If a checksum was requested it is computed as the data is written, then stored.
+    /// If it is to be cleared the checksum left from earlier data is removed.
+    pub(crate) fn write(&self, connection: &Connection, row_id: i64) -> Result<(), Error> {
+        if let Some(ChecksumAction::Store(storage)) = &self.checksum {
+            let hash = self.write_hashed(connection, row_id)?;
+            return storage.store(connection, &self.table, &self.field, row_id, &hash);
+        }
+
+        let mut blob = self.open(connection, row_id)?;
+
+        match &self.data {
+            BlobData::Memory(data) => blob.write_at(data.as_slice(), 0).map_err(|e| Error::from_blob(&self.table, &self.field, e))?,
+            BlobData::File { path, size } => BlobValue::copy_file(path, *size, &mut blob).map_err(|e| Error::from_blob(&self.table, &self.field, e))?
+        };
+
+        drop(blob);
+
+        match &self.checksum {
+            Some(ChecksumAction::Clear(storage)) => storage.clear(connection, &self.table, &self.field, row_id),
+            _ => Ok(())
+        }
+    }
+
+    /// Write the blob like `write`, without storing a checksum, and return the SHA-256 of the data written.
+    /// The blob handle is closed before returning, so the row can be updated afterwards.
+    pub(crate) fn write_hashed(&self, connection: &Connection, row_id: i64) -> Result<String, Error> {
+        let mut blob = self.open(connection, row_id)?;
+
+        match &self.data {
+            BlobData::Memory(data) => {
+                blob.write_at(data.as_slice(), 0).map_err(|e| Error::from_blob(&self.table, &self.field, e))?;
+                Ok(checksum::sha256(data))
+            }
+            BlobData::File { path, size } => {
+                let mut writer = HashingWriter::create(&mut blob);
+                BlobValue::copy_file(path, *size, &mut writer).map_err(|e| Error::from_blob(&self.table, &self.field, e))?;
+                Ok(writer.finish())
+            }
+        }
+    }
+
+    fn open<'a>(&self, connection: &'a Connection, row_id: i64) -> Result<Blob<'a>, Error> {
+        connection.blob_open(self.table.get_database_name(), self.table.get_name(), self.field.get_name(), row_id, false)
+            .map_err(|e| Error::from_blob(&self.table, &self.field, e))
+    }
 
+    /// Compute the SHA-256 of the data. File data is streamed, not loaded into memory.
+    pub(crate) fn compute_checksum(&self) -> Result<String, Error> {
+        match &self.data {
+            BlobData::Memory(data) => Ok(checksum::sha256(data)),
+            BlobData::File { path, size } => {
+                let mut writer = HashingWriter::create(io::sink());
+                BlobValue::copy_file(path, *size, &mut writer).map_err(|e| Error::from_blob(&self.table, &self.field, e))?;
+                Ok(writer.finish())
+            }
+        }
+    }
+
+    fn copy_file(path: &Path, size: u64, blob: &mut impl io::Write) -> io::Result<()> {
+        let file = File::open(path)?;
+        let copied = io::copy(&mut file.take(size), blob)?;
+
+        match copied == size {
+            true => Ok(()),
+            false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File was truncated after the blob was created"))
+        }
     }
 }
 
 impl Criteria {
-    pub fn handle(criteria: Criteria) -> (String, Option<Vec<BoxedValue>>) {
+    /// Criteria on a field. Use `Identifier::qualified` for a field of a specific table, i.e. in a join.
+    pub fn field<T>(field: T) -> CriteriaField where T : Into<Identifier> {
+        CriteriaField {
+            field: field.into()
+        }
+    }
+
+    /// Raw sql with `:name` placeholders, i.e. `Criteria::raw_named("age > :min", vec![NamedValue::create("min", 30)])`.
+    pub fn raw_named<T>(sql: T, values: Vec<NamedValue>) -> Criteria where T : Into<String> {
+        Criteria::Items(vec![CriteriaItemType::RawNamed { sql: sql.into(), values }])
+    }
+
+    /// Compile the criteria into the body of a `WHERE` clause and its parameters.
+    pub fn handle(criteria: Criteria) -> Result<(String, Option<Vec<BoxedValue>>), Error> {
+        let mut params = Params::create();
+        let sql = Criteria::bind(criteria, &mut params)?;
+
+        Ok((sql, vec_to_optional(params.finish()?)))
+    }
+
+    /// Compile the criteria into the body of a `WHERE` clause, binding its values to `params`
+    /// after any already bound elsewhere in the statement (i.e. an `UPDATE`'s `SET` values).
+    pub fn bind(criteria: Criteria, params: &mut Params) -> Result<String, Error> {
         match criteria {
-            Criteria::Raw(s) => (s, None),
-            Criteria::Items(_) => unimplemented!()
+            Criteria::Raw(s) => Ok(s),
+            Criteria::Items(items) => CriteriaItemType::handle_all(items, &Logic::And, params)
+        }
+    }
+}
+
+impl CriteriaItemType {
+    fn handle(item: CriteriaItemType, params: &mut Params) -> Result<String, Error> {
+        match item {
+            CriteriaItemType::Item(item) => CriteriaItem::handle(item, params),
+            CriteriaItemType::Raw(s) => Ok(format!("({})", s)),
+            CriteriaItemType::RawNamed { sql, values } => Ok(format!("({})", params.bind_named(&sql, values)?)),
+            CriteriaItemType::Group { items, logic } => Ok(format!("({})", CriteriaItemType::handle_all(items, &logic, params)?)),
+            CriteriaItemType::Exists { select, negated: false } => Ok(format!("EXISTS ({})", select.bind(params)?)),
+            CriteriaItemType::Exists { select, negated: true } => Ok(format!("NOT EXISTS ({})", select.bind(params)?))
+        }
+    }
+
+    fn handle_all(items: Vec<CriteriaItemType>, logic: &Logic, params: &mut Params) -> Result<String, Error> {
+        // An empty group has no conditions, so it falls back to the identity for its logic.
+        if items.is_empty() {
+            return match logic {
+                Logic::And => Ok(String::from("1")),
+                Logic::Or => Ok(String::from("0"))
+            };
+        }
+
+        let mut parts = Vec::new();
+
+        for item in items {
+            parts.push(CriteriaItemType::handle(item, params)?);
+        }
+
+        Ok(parts.join(logic.get_sql()))
+    }
+}
+
+impl Logic {
+    fn matches(&self, other: &Logic) -> bool {
+        matches!((self, other), (Logic::And, Logic::And) | (Logic::Or, Logic::Or))
+    }
+
+    fn get_sql(&self) -> &'static str {
+        match self {
+            Logic::And => " AND ",
+            Logic::Or => " OR "
+        }
+    }
+}
+
+impl CriteriaItem {
+    pub fn create<T>(field: T, operator: Operator, values: Vec<CriteriaItemValue>) -> Result<CriteriaItem, Error> where T : Into<Identifier> {
+        operator.check_operands(values.len())?;
+
+        Ok(CriteriaItem {
+            field: field.into(),
+            operator,
+            values
+        })
+    }
+
+    fn handle(item: CriteriaItem, params: &mut Params) -> Result<String, Error> {
+        let mut operands = Vec::new();
+        let field = item.field.get_sql()?;
+
+        // `IN (SELECT ...)` tests every row of the subquery, `IN ((SELECT ...))` would only test the first.
+        let subquery = matches!(item.values.as_slice(), [CriteriaItemValue::Select(_)]);
+
+        for value in item.values {
+            operands.push(CriteriaItemValue::handle(value, params)?);
+        }
+
+        Ok(match item.operator {
+            Operator::In | Operator::NotIn if subquery => format!("{} {} {}", field, item.operator.get_sql(), operands[0]),
+            Operator::In | Operator::NotIn => format!("{} {} ({})", field, item.operator.get_sql(), operands.join(", ")),
+            Operator::Between => format!("{} BETWEEN {} AND {}", field, operands[0], operands[1]),
+            Operator::IsNull | Operator::IsNotNull => format!("{} {}", field, item.operator.get_sql()),
+            _ => format!("{} {} {}", field, item.operator.get_sql(), operands[0])
+        })
+    }
+}
+
+impl CriteriaItemValue {
+    pub fn create(value: impl ToSql + Send + 'static) -> CriteriaItemValue {
+        CriteriaItemValue::Value(Box::new(value))
+    }
+
+    pub fn select(select: Select) -> CriteriaItemValue {
+        CriteriaItemValue::Select(Box::new(select))
+    }
+
+    fn handle(value: CriteriaItemValue, params: &mut Params) -> Result<String, Error> {
+        match value {
+            CriteriaItemValue::Raw(raw) => Ok(raw),
+            CriteriaItemValue::Value(value) => Ok(params.bind(value)),
+            CriteriaItemValue::Select(select) => Ok(format!("({})", select.bind(params)?))
+        }
+    }
+}
+
+impl Operator {
+    pub fn get_sql(&self) -> &'static str {
+        match self {
+            Operator::Equal => "=",
+            Operator::NotEqual => "<>",
+            Operator::LessThan => "<",
+            Operator::LessThanOrEqual => "<=",
+            Operator::GreaterThan => ">",
+            Operator::GreaterThanOrEqual => ">=",
+            Operator::Like => "LIKE",
+            Operator::Glob => "GLOB",
+            Operator::In => "IN",
+            Operator::NotIn => "NOT IN",
+            Operator::Between => "BETWEEN",
+            Operator::IsNull => "IS NULL",
+            Operator::IsNotNull => "IS NOT NULL"
+        }
+    }
+
+    /// Check the operator can be used with `count` operands.
+    pub fn check_operands(&self, count: usize) -> Result<(), Error> {
+        match (self, count) {
+            (Operator::IsNull, 0) | (Operator::IsNotNull, 0) => Ok(()),
+            (Operator::IsNull, _) | (Operator::IsNotNull, _) => Err(Error::InvalidQuery("`IS NULL` and `IS NOT NULL` do not take any operands.")),
+            (Operator::Between, 2) => Ok(()),
+            (Operator::Between, _) => Err(Error::InvalidQuery("`BETWEEN` requires exactly two operands.")),
+            (Operator::In, 0) | (Operator::NotIn, 0) => Err(Error::InvalidQuery("`IN` and `NOT IN` require at least one operand.")),
+            (Operator::In, _) | (Operator::NotIn, _) => Ok(()),
+            (_, 1) => Ok(()),
+            (_, _) => Err(Error::InvalidQuery("Comparison operators require exactly one operand."))
+        }
+    }
+}
+
+impl CriteriaField {
+    pub fn eq(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::Equal, value)
+    }
+
+    pub fn ne(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::NotEqual, value)
+    }
+
+    pub fn lt(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::LessThan, value)
+    }
+
+    pub fn le(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::LessThanOrEqual, value)
+    }
+
+    pub fn gt(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::GreaterThan, value)
+    }
+
+    pub fn ge(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::GreaterThanOrEqual, value)
+    }
+
+    pub fn like(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::Like, value)
+    }
+
+    pub fn glob(self, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.single(Operator::Glob, value)
+    }
+
+    pub fn is_in(self, values: Vec<impl ToSql + Send + 'static>) -> CriteriaBuilder {
+        self.many(Operator::In, values)
+    }
+
+    pub fn not_in(self, values: Vec<impl ToSql + Send + 'static>) -> CriteriaBuilder {
+        self.many(Operator::NotIn, values)
+    }
+
+    pub fn between(self, low: impl ToSql + Send + 'static, high: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.operator(Operator::Between, vec![CriteriaItemValue::create(low), CriteriaItemValue::create(high)])
+    }
+
+    /// `field IN (SELECT ...)`, the subquery should select a single column.
+    pub fn in_select(self, select: Select) -> CriteriaBuilder {
+        self.operator(Operator::In, vec![CriteriaItemValue::select(select)])
+    }
+
+    pub fn not_in_select(self, select: Select) -> CriteriaBuilder {
+        self.operator(Operator::NotIn, vec![CriteriaItemValue::select(select)])
+    }
+
+    pub fn is_null(self) -> CriteriaBuilder {
+        self.operator(Operator::IsNull, Vec::new())
+    }
+
+    pub fn is_not_null(self) -> CriteriaBuilder {
+        self.operator(Operator::IsNotNull, Vec::new())
+    }
+
+    /// Use any operator with the supplied operands, including `CriteriaItemValue::Raw` sql.
+    pub fn operator(self, operator: Operator, values: Vec<CriteriaItemValue>) -> CriteriaBuilder {
+        CriteriaBuilder {
+            item: CriteriaItem::create(self.field, operator, values).map(CriteriaItemType::Item)
+        }
+    }
+
+    fn single(self, operator: Operator, value: impl ToSql + Send + 'static) -> CriteriaBuilder {
+        self.operator(operator, vec![CriteriaItemValue::create(value)])
+    }
+
+    fn many(self, operator: Operator, values: Vec<impl ToSql + Send + 'static>) -> CriteriaBuilder {
+        let values = values.into_iter().map(CriteriaItemValue::create).collect();
+        self.operator(operator, values)
+    }
+}
+
+impl CriteriaBuilder {
+    pub fn raw<T>(sql: T) -> CriteriaBuilder where T : Into<String> {
+        CriteriaBuilder {
+            item: Ok(CriteriaItemType::Raw(sql.into()))
+        }
+    }
+
+    /// Raw sql with `:name` placeholders, bound to `values`.
+    pub fn raw_named<T>(sql: T, values: Vec<NamedValue>) -> CriteriaBuilder where T : Into<String> {
+        CriteriaBuilder {
+            item: Ok(CriteriaItemType::RawNamed { sql: sql.into(), values })
+        }
+    }
+
+    pub fn exists(select: Select) -> CriteriaBuilder {
+        CriteriaBuilder {
+            item: Ok(CriteriaItemType::Exists { select: Box::new(select), negated: false })
+        }
+    }
+
+    pub fn not_exists(select: Select) -> CriteriaBuilder {
+        CriteriaBuilder {
+            item: Ok(CriteriaItemType::Exists { select: Box::new(select), negated: true })
+        }
+    }
+
+    pub fn and(self, other: CriteriaBuilder) -> CriteriaBuilder {
+        self.combine(other, Logic::And)
+    }
+
+    pub fn or(self, other: CriteriaBuilder) -> CriteriaBuilder {
+        self.combine(other, Logic::Or)
+    }
+
+    pub fn build(self) -> Result<Criteria, Error> {
+        Ok(Criteria::Items(vec![self.item?]))
+    }
+
+    fn combine(self, other: CriteriaBuilder, logic: Logic) -> CriteriaBuilder {
+        let item = match (self.item, other.item) {
+            (Err(e), _) | (_, Err(e)) => Err(e),
+            // Chained calls with the same logic extend the current group rather than nesting,
+            // so `a.and(b).and(c)` becomes `(a AND b AND c)`.
+            (Ok(CriteriaItemType::Group { mut items, logic: current }), Ok(other)) if current.matches(&logic) => {
+                items.push(other);
+                Ok(CriteriaItemType::Group { items, logic })
+            }
+            (Ok(item), Ok(other)) => Ok(CriteriaItemType::Group { items: vec![item, other], logic })
+        };
+
+        CriteriaBuilder {
+            item
         }
     }
 }
 
 impl Value {
-    pub fn create<T>(field: T, value: impl ToSql + Send + 'static) -> Value where T : Into<String> {
+    pub fn create<T>(field: T, value: impl ToSql + Send + 'static) -> Value where T : Into<Identifier> {
+        Value {
+            field: field.into(),
+            value: ValueType::BoxedValue(Box::new(value)),
+            checksum: None
+        }
+    }
+
+    pub fn create_blob<T>(field: T, value: BlobRef) -> Value where T : Into<Identifier> {
+        Value {
+            field: field.into(),
+            value: ValueType::Blob(value),
+            checksum: None
+        }
+    }
+
+    /// A blob value that also stores the SHA-256 of its data, so it can be verified when read.
+    pub fn create_blob_with_checksum<T>(field: T, value: BlobRef, storage: ChecksumStorage) -> Value where T : Into<Identifier> {
         Value {
             field: field.into(),
-            value: ValueType::BoxedValue(Box::new(value))
+            value: ValueType::Blob(value),
+            checksum: Some(ChecksumAction::Store(storage))
         }
     }
 
-    pub fn create_blob<T>(field: T, value: BlobRef) -> Value where T : Into<String> {
+    /// A blob value for a field with checksums in `storage`, removing the checksum of the earlier data
+    /// so the new data isn't verified against it.
+    pub fn create_blob_clearing_checksum<T>(field: T, value: BlobRef, storage: ChecksumStorage) -> Value where T : Into<Identifier> {
         Value {
             field: field.into(),
-            value: ValueType::Blob(value)
+            value: ValueType::Blob(value),
+            checksum: Some(ChecksumAction::Clear(storage))
         }
     }
-}
\ No newline at end of file
+}
+
+impl OnConflict {
+    /// An empty target, i.e. `Vec::<&str>::new()`, matches any conflict.
+    pub fn do_nothing<T>(target: Vec<T>) -> OnConflict where T : Into<Identifier> {
+        OnConflict::DoNothing { target: target.into_iter().map(|field| field.into()).collect() }
+    }
+
+    pub fn do_update<T>(target: Vec<T>, updates: Vec<ConflictUpdate>) -> OnConflict where T : Into<Identifier> {
+        OnConflict::DoUpdate { target: target.into_iter().map(|field| field.into()).collect(), updates }
+    }
+
+    /// Compile the conflict clause, binding any update values to `params`.
+    pub(crate) fn bind(self, params: &mut Params) -> Result<ConflictClause, Error> {
+        let prefix = match &self {
+            OnConflict::Rollback => "INSERT OR ROLLBACK INTO",
+            OnConflict::Abort => "INSERT OR ABORT INTO",
+            OnConflict::Fail => "INSERT OR FAIL INTO",
+            OnConflict::Ignore => "INSERT OR IGNORE INTO",
+            OnConflict::Replace => "INSERT OR REPLACE INTO",
+            OnConflict::DoNothing { .. } | OnConflict::DoUpdate { .. } => "INSERT INTO"
+        };
+
+        let (sql, updates) = match self {
+            OnConflict::DoNothing { target } => (format!(" ON CONFLICT{} DO NOTHING", OnConflict::get_target(&target)?), false),
+            OnConflict::DoUpdate { target, updates } => {
+                if updates.is_empty() {
+                    return Err(Error::InvalidQuery("OnConflict::DoUpdate needs at least one update, use DoNothing instead"));
+                }
+
+                let mut sets = Vec::new();
+
+                for update in updates {
+                    let field = update.field.get_name_sql()?;
+
+                    sets.push(match update.value {
+                        ConflictValue::Excluded => format!("{} = excluded.{}", field, field),
+                        ConflictValue::Raw(sql) => format!("{} = {}", field, sql),
+                        ConflictValue::Value(value) => format!("{} = {}", field, params.bind(value))
+                    });
+                }
+
+                (format!(" ON CONFLICT{} DO UPDATE SET {}", OnConflict::get_target(&target)?, sets.join(", ")), true)
+            }
+            _ => (String::new(), false)
+        };
+
+        Ok(ConflictClause {
+            prefix,
+            sql,
+            updates,
+        })
+    }
+
+    fn get_target(target: &[Identifier]) -> Result<String, Error> {
+        let target = target.iter().map(|field| field.get_name_sql()).collect::<Result<Vec<String>, Error>>()?;
+
+        Ok(match target.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", target.join(", "))
+        })
+    }
+}
+
+impl ConflictUpdate {
+    /// Set the field to the value that failed to insert.
+    pub fn excluded<T>(field: T) -> ConflictUpdate where T : Into<Identifier> {
+        ConflictUpdate { field: field.into(), value: ConflictValue::Excluded }
+    }
+
+    pub fn value<T>(field: T, value: impl ToSql + Send + 'static) -> ConflictUpdate where T : Into<Identifier> {
+        ConflictUpdate { field: field.into(), value: ConflictValue::Value(Box::new(value)) }
+    }
+
+    pub fn raw<T, U>(field: T, sql: U) -> ConflictUpdate where T : Into<Identifier>, U : Into<String> {
+        ConflictUpdate { field: field.into(), value: ConflictValue::Raw(sql.into()) }
+    }
+}
+
+impl Returning {
+    /// Return the fields from each row written, mapped by `mapper`.
+    pub fn create<T, F, I>(field_names: Vec<I>, mapper: F) -> Returning
+        where F: Fn(&Row<'_>) -> Result<T, io::Error> + Send + 'static, T: Send + 'static, I: Into<Identifier> {
+        Returning::handle_create(Some(field_names.into_iter().map(|field| field.into()).collect()), mapper)
+    }
+
+    /// Return every column, `RETURNING *`.
+    pub fn all<T, F>(mapper: F) -> Returning where F: Fn(&Row<'_>) -> Result<T, io::Error> + Send + 'static, T: Send + 'static {
+        Returning::handle_create(None, mapper)
+    }
+
+    fn handle_create<T, F>(fields: Option<Vec<Identifier>>, mapper: F) -> Returning where F: Fn(&Row<'_>) -> Result<T, io::Error> + Send + 'static, T: Send + 'static {
+        Returning {
+            fields,
+            mapper: Box::new(move |row| mapper(row).map(|value| Box::new(value) as ReturnedRow)),
+        }
+    }
+
+    pub(crate) fn get_sql(&self) -> Result<String, Error> {
+        match &self.fields {
+            Some(fields) => {
+                let fields = fields.iter().map(|field| field.get_name_sql()).collect::<Result<Vec<String>, Error>>()?;
+                Ok(format!(" RETURNING {}", fields.join(", ")))
+            }
+            None => Ok(String::from(" RETURNING *"))
+        }
+    }
+
+    /// Execute a statement with this clause, returning the rows affected and the mapped rows.
+    pub(crate) fn query<P>(&self, connection: &Connection, sql: &str, params: P) -> Result<(usize, Vec<ReturnedRow>), Error> where P: IntoIterator, P::Item: ToSql {
+        let mut statement = connection.prepare(sql).map_err(|e| Error::from_prepare(sql, e))?;
+        let mut rows = statement.query(params).map_err(|e| Error::from_execute(sql, e))?;
+        let mut returned = Vec::new();
+
+        while let Some(row) = rows.next().map_err(|e| Error::from_execute(sql, e))? {
+            returned.push((self.mapper)(row).map_err(|e| Error::Mapping { sql: String::from(sql), source: e })?);
+        }
+
+        // Every row has been stepped through, so the statement has finished and its changes are counted.
+        let changes: i64 = connection.query_row("SELECT changes();", NO_PARAMS, |row| row.get(0))
+            .map_err(|e| Error::from_execute("SELECT changes();", e))?;
+
+        Ok((changes as usize, returned))
+    }
+}
+
+pub(crate) fn vec_to_optional<T>(vec: Vec<T>) -> Option<Vec<T>> {
+    match vec.is_empty() {
+        true => None,
+        false => Some(vec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(builder: CriteriaBuilder) -> (String, usize) {
+        let (sql, params) = Criteria::handle(builder.build().unwrap()).unwrap();
+        (sql, params.map_or(0, |params| params.len()))
+    }
+
+    fn get_matches(builder: CriteriaBuilder) -> Vec<Option<i64>> {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE t (v INTEGER); INSERT INTO t VALUES (1), (2), (NULL), (4);").unwrap();
+
+        let (sql, params) = Criteria::handle(builder.build().unwrap()).unwrap();
+        let sql = format!("SELECT v FROM t WHERE {} ORDER BY v", sql);
+        let mut statement = connection.prepare(&sql).unwrap();
+        let rows = statement.query_map(params.unwrap_or_default(), |row| row.get(0)).unwrap();
+
+        rows.map(|v| v.unwrap()).collect()
+    }
+
+    #[test]
+    fn numbers_placeholders_in_order() {
+        let (sql, count) = handle(Criteria::field("a").eq(1).and(Criteria::field("b").between(2, 3)).and(Criteria::field("c").is_in(vec![4, 5])));
+
+        assert_eq!(sql, "(`a` = ?1 AND `b` BETWEEN ?2 AND ?3 AND `c` IN (?4, ?5))");
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn numbers_placeholders_after_an_offset() {
+        let mut params = Params::create();
+        params.bind(Box::new("set"));
+
+        let sql = Criteria::bind(Criteria::field("a").eq(1).or(Criteria::field("b").ne(2)).build().unwrap(), &mut params).unwrap();
+
+        assert_eq!(sql, "(`a` = ?2 OR `b` <> ?3)");
+        assert_eq!(params.get_count(), 3);
+    }
+
+    #[test]
+    fn groups_mixed_logic_with_parentheses() {
+        let (sql, _) = handle(Criteria::field("a").eq(1).and(Criteria::field("b").eq(2)).or(Criteria::field("c").eq(3)));
+        assert_eq!(sql, "((`a` = ?1 AND `b` = ?2) OR `c` = ?3)");
+
+        let (sql, _) = handle(Criteria::field("a").eq(1).and(Criteria::field("b").eq(2).or(Criteria::field("c").eq(3))));
+        assert_eq!(sql, "(`a` = ?1 AND (`b` = ?2 OR `c` = ?3))");
+
+        let (sql, _) = handle(CriteriaBuilder::raw("a = 1 OR b = 2").and(Criteria::field("c").eq(3)));
+        assert_eq!(sql, "((a = 1 OR b = 2) AND `c` = ?1)");
+    }
+
+    #[test]
+    fn applies_or_grouping_to_rows() {
+        let matches = get_matches(Criteria::field("v").eq(1).or(Criteria::field("v").gt(1).and(Criteria::field("v").lt(4))));
+
+        assert_eq!(matches, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn rejects_empty_in_lists() {
+        assert!(matches!(Criteria::field("a").is_in(Vec::<i64>::new()).build(), Err(Error::InvalidQuery(_))));
+        assert!(matches!(Criteria::field("a").not_in(Vec::<i64>::new()).build(), Err(Error::InvalidQuery(_))));
+
+        // The error is kept when combined with other criteria.
+        assert!(Criteria::field("b").eq(1).or(Criteria::field("a").is_in(Vec::<i64>::new())).build().is_err());
+    }
+
+    #[test]
+    fn checks_operand_counts() {
+        assert!(CriteriaItem::create("a", Operator::Between, vec![CriteriaItemValue::create(1)]).is_err());
+        assert!(CriteriaItem::create("a", Operator::IsNull, vec![CriteriaItemValue::create(1)]).is_err());
+        assert!(CriteriaItem::create("a", Operator::Equal, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn handles_null() {
+        let (sql, count) = handle(Criteria::field("a").is_null().or(Criteria::field("b").is_not_null()));
+        assert_eq!(sql, "(`a` IS NULL OR `b` IS NOT NULL)");
+        assert_eq!(count, 0);
+
+        assert_eq!(get_matches(Criteria::field("v").is_null()), vec![None]);
+        assert_eq!(get_matches(Criteria::field("v").is_not_null()), vec![Some(1), Some(2), Some(4)]);
+
+        // Comparing with NULL never matches, `is_null` has to be used instead.
+        assert!(get_matches(Criteria::field("v").eq(Option::<i64>::None)).is_empty());
+        assert_eq!(get_matches(Criteria::field("v").not_in(vec![1])), vec![Some(2), Some(4)]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+/// The error type for all `rusq` operations.
+/// Variants wrapping a `rusqlite` failure keep the sql that caused it and the original error as the source.
+#[derive(Debug)]
+pub enum Error {
+    /// A connection to the database could not be opened.
+    Connection { path: String, source: rusqlite::Error },
+    /// The sql could not be prepared, i.e. a syntax error or a missing table.
+    Prepare { sql: String, source: rusqlite::Error },
+    /// The parameters could not be bound to the statement.
+    Bind { sql: String, source: rusqlite::Error },
+    /// The statement violated a constraint (`UNIQUE`, `NOT NULL`, `FOREIGN KEY` etc.).
+    ConstraintViolation { sql: String, source: rusqlite::Error },
+    /// The statement failed for any other reason.
+    Execute { sql: String, source: rusqlite::Error },
+    /// The statement matched an unexpected number of rows, i.e. a blob update that matched no rows.
+    UnexpectedRowCount { sql: String, matched: usize },
+    /// Blob data could not be read or written.
+    BlobIo { table: String, field: String, source: std::io::Error },
+    /// A blob's data doesn't match its stored checksum, or no checksum was stored.
+    ChecksumMismatch { table: String, field: String, row_id: i64, expected: Option<String>, actual: String },
+    /// A row could not be mapped to a value.
+    Mapping { sql: String, source: std::io::Error },
+    /// A query in a transaction failed, `index` is its position in the transaction.
+    /// The whole transaction has been rolled back.
+    Transaction { index: usize, source: Box<Error> },
+    /// A query in a `Savepoint` failed, `index` is its position in the savepoint.
+    /// Only the savepoint's queries have been rolled back.
+    Savepoint { index: usize, source: Box<Error> },
+    /// The `db_writer` channel is closed.
+    ChannelClosed,
+    /// The `db_writer` queue is full.
+    QueueFull,
+    /// A query panicked while being executed by the `db_writer`.
+    /// The writer recovered and reopened its connection.
+    WriterPanicked,
+    /// The operation did not complete in time.
+    Timeout,
+    /// The group commit batch the query was part of failed, so none of it was committed.
+    Batch(String),
+    /// The query could not be built from the values supplied.
+    InvalidQuery(&'static str),
+    /// The statement needs more parameters than SQLite allows.
+    TooManyParameters { count: usize, limit: usize },
+    /// A `:name` placeholder in raw sql has no value.
+    UnboundParameter(String),
+    /// A named value was supplied but its placeholder isn't in the sql.
+    UnusedParameter(String),
+    /// A table or column name is not allowed.
+    InvalidIdentifier { name: String, reason: &'static str },
+    /// A table or column is not in the live schema.
+    UnknownIdentifier(String),
+    /// The returned rows aren't the type that was asked for.
+    ReturnedType(&'static str),
+    /// A struct could not be converted to values.
+    Serialize(String),
+    /// The logger could not be created.
+    Logger(&'static str),
+}
+
+impl Error {
+    /// Classify an error returned while binding or executing a prepared statement.
+    pub(crate) fn from_execute(sql: &str, source: rusqlite::Error) -> Error {
+        let sql = String::from(sql);
+
+        match &source {
+            rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation => Error::ConstraintViolation { sql, source },
+            rusqlite::Error::InvalidParameterCount(_, _)
+            | rusqlite::Error::InvalidParameterName(_)
+            | rusqlite::Error::ToSqlConversionFailure(_) => Error::Bind { sql, source },
+            _ => Error::Execute { sql, source }
+        }
+    }
+
+    pub(crate) fn from_prepare(sql: &str, source: rusqlite::Error) -> Error {
+        Error::Prepare { sql: String::from(sql), source }
+    }
+
+    /// I/O errors are kept as they are, so their kind (i.e. `NotFound`) can still be matched on.
+    pub(crate) fn from_blob<T, U, S>(table: T, field: U, source: S) -> Error where T : fmt::Display, U : fmt::Display, S : Into<Box<dyn std::error::Error + Send + Sync>> {
+        let source = match source.into().downcast::<std::io::Error>() {
+            Ok(source) => *source,
+            Err(source) => std::io::Error::other(source)
+        };
+
+        Error::BlobIo {
+            table: table.to_string(),
+            field: field.to_string(),
+            source,
+        }
+    }
+
+    /// The error to report to each request in a failed group commit batch.
+    pub(crate) fn for_batch(&self) -> Error {
+        match self {
+            Error::WriterPanicked => Error::WriterPanicked,
+            e => Error::Batch(e.to_string())
+        }
+    }
+
+    /// The sql that caused the error, if any.
+    pub fn get_sql(&self) -> Option<&str> {
+        match self {
+            Error::Prepare { sql, .. }
+            | Error::Bind { sql, .. }
+            | Error::ConstraintViolation { sql, .. }
+            | Error::Execute { sql, .. }
+            | Error::UnexpectedRowCount { sql, .. }
+            | Error::Mapping { sql, .. } => Some(sql.as_str()),
+            Error::Transaction { source, .. } | Error::Savepoint { source, .. } => source.get_sql(),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection { path, source } => write!(f, "Could not open connection to `{}`: {}", path, source),
+            Error::Prepare { sql, source } => write!(f, "Could not prepare `{}`: {}", sql, source),
+            Error::Bind { sql, source } => write!(f, "Could not bind parameters for `{}`: {}", sql, source),
+            Error::ConstraintViolation { sql, source } => write!(f, "Constraint violated by `{}`: {}", sql, source),
+            Error::Execute { sql, source } => write!(f, "Could not execute `{}`: {}", sql, source),
+            Error::UnexpectedRowCount { sql, matched } => write!(f, "Unexpected number of rows ({}) matched by `{}`", matched, sql),
+            Error::BlobIo { table, field, source } => write!(f, "Blob I/O failed for `{}.{}`: {}", table, field, source),
+            Error::ChecksumMismatch { table, field, row_id, expected: Some(expected), actual } => write!(f, "Checksum mismatch for `{}.{}` row {}, expected `{}` but found `{}`", table, field, row_id, expected, actual),
+            Error::ChecksumMismatch { table, field, row_id, expected: None, .. } => write!(f, "No checksum stored for `{}.{}` row {}", table, field, row_id),
+            Error::Mapping { sql, source } => write!(f, "Could not map row from `{}`: {}", sql, source),
+            Error::Transaction { index, source } => write!(f, "Transaction rolled back, query {} failed: {}", index, source),
+            Error::Savepoint { index, source } => write!(f, "Savepoint rolled back, query {} failed: {}", index, source),
+            Error::ChannelClosed => write!(f, "The `db_writer` channel is closed."),
+            Error::QueueFull => write!(f, "The `db_writer` queue is full."),
+            Error::WriterPanicked => write!(f, "The query panicked in the `db_writer`."),
+            Error::Timeout => write!(f, "The operation timed out."),
+            Error::Batch(message) => write!(f, "Batch could not be committed: {}", message),
+            Error::InvalidQuery(message) => write!(f, "Invalid query: {}", message),
+            Error::TooManyParameters { count, limit } => write!(f, "The query needs {} parameters, more than the limit of {}", count, limit),
+            Error::UnboundParameter(name) => write!(f, "No value supplied for parameter `:{}`", name),
+            Error::UnusedParameter(name) => write!(f, "Parameter `:{}` isn't used in the sql", name),
+            Error::InvalidIdentifier { name, reason } => write!(f, "Invalid identifier `{}`: {}", name, reason),
+            Error::UnknownIdentifier(name) => write!(f, "`{}` is not in the schema", name),
+            Error::ReturnedType(name) => write!(f, "The returned rows are not `{}`", name),
+            Error::Serialize(message) => write!(f, "Could not convert struct to values: {}", message),
+            Error::Logger(message) => write!(f, "Could not create logger: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection { source, .. }
+            | Error::Prepare { source, .. }
+            | Error::Bind { source, .. }
+            | Error::ConstraintViolation { source, .. }
+            | Error::Execute { source, .. } => Some(source),
+            Error::BlobIo { source, .. } | Error::Mapping { source, .. } => Some(source),
+            Error::Transaction { source, .. } | Error::Savepoint { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+use rusqlite::{Connection, DatabaseName};
+use crate::error::Error;
+
+/// A table or column name, quoted whenever it is written into sql,
+/// so reserved words, spaces and quotes in names are safe.
+/// Names are quoted with backticks rather than `"`, which SQLite reads as a string literal
+/// when the name doesn't resolve, so a misspelled column fails instead of matching a constant.
+/// Strings convert into unqualified identifiers as they are, an invalid name fails when it is written into sql.
+/// Use `Identifier::qualified` or `Identifier::parse` for a table in an attached database (`aux.table`)
+/// or a column of a table (`table.column`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identifier {
+    schema: Option<String>,
+    name: String,
+    /// Why a name converted from a string isn't allowed.
+    invalid: Option<&'static str>,
+}
+
+impl Identifier {
+    /// Create an identifier, failing if the name is empty or contains a NUL character.
+    /// Use this for names that come from configuration or user input.
+    pub fn create<T>(name: T) -> Result<Identifier, Error> where T : Into<String> {
+        let name = Identifier::check_part(name.into())?;
+
+        Ok(Identifier {
+            schema: None,
+            name,
+            invalid: None,
+        })
+    }
+
+    /// Create a name qualified by a schema or table, i.e. `Identifier::qualified("aux", "table")`.
+    pub fn qualified<T, U>(schema: T, name: U) -> Result<Identifier, Error> where T : Into<String>, U : Into<String> {
+        let schema = Identifier::check_part(schema.into())?;
+        let name = Identifier::check_part(name.into())?;
+
+        Ok(Identifier {
+            schema: Some(schema),
+            name,
+            invalid: None,
+        })
+    }
+
+    /// Parse `name` or `schema.name`. Either part may be quoted with `"`, `` ` `` or `[]`,
+    /// i.e. `aux."my table"`, so a quoted part can contain a `.`.
+    pub fn parse(s: &str) -> Result<Identifier, Error> {
+        let chars: Vec<char> = s.chars().collect();
+        let (first, end) = Identifier::parse_part(s, &chars, 0)?;
+
+        match chars.get(end) {
+            None => Identifier::create(first),
+            Some('.') => {
+                let (second, end) = Identifier::parse_part(s, &chars, end + 1)?;
+
+                match end == chars.len() {
+                    true => Identifier::qualified(first, second),
+                    false => Err(Error::InvalidIdentifier { name: String::from(s), reason: "Only one `.` is allowed outside quotes" })
+                }
+            }
+            Some(_) => Err(Error::InvalidIdentifier { name: String::from(s), reason: "Unexpected characters after a quoted name" })
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// The quoted identifier, i.e. `` `aux`.`table` ``, failing if the name isn't allowed.
+    pub fn get_sql(&self) -> Result<String, Error> {
+        self.check()?;
+
+        match &self.schema {
+            None => Ok(Identifier::quote(&self.name)),
+            Some(schema) => Ok(format!("{}.{}", Identifier::quote(schema), Identifier::quote(&self.name)))
+        }
+    }
+
+    /// Only the quoted name, for places SQLite doesn't allow a qualified name, i.e. the columns of an `INSERT`.
+    pub fn get_name_sql(&self) -> Result<String, Error> {
+        self.check()?;
+        Ok(Identifier::quote(&self.name))
+    }
+
+    /// Check the name is allowed, as `Identifier::create` does.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.invalid {
+            Some(reason) => Err(Error::InvalidIdentifier { name: self.name.clone(), reason }),
+            None => Ok(())
+        }
+    }
+
+    /// The database a table is in, for opening blobs.
+    pub(crate) fn get_database_name(&self) -> DatabaseName<'_> {
+        match self.schema.as_deref() {
+            None => DatabaseName::Main,
+            Some(schema) if schema.eq_ignore_ascii_case("main") => DatabaseName::Main,
+            Some(schema) if schema.eq_ignore_ascii_case("temp") => DatabaseName::Temp,
+            Some(schema) => DatabaseName::Attached(schema)
+        }
+    }
+
+    /// Check a table or view with this name exists in the live schema.
+    pub fn check_table(&self, connection: &Connection) -> Result<(), Error> {
+        match self.get_columns(connection)?.is_empty() {
+            true => Err(Error::UnknownIdentifier(self.to_string())),
+            false => Ok(())
+        }
+    }
+
+    /// Check this column exists in `table` in the live schema.
+    pub fn check_column(&self, table: &Identifier, connection: &Connection) -> Result<(), Error> {
+        self.check()?;
+        let columns = table.get_columns(connection)?;
+
+        match columns.iter().any(|column| column.eq_ignore_ascii_case(&self.name)) {
+            true => Ok(()),
+            false => Err(Error::UnknownIdentifier(format!("{}.{}", table, self.name)))
+        }
+    }
+
+    fn get_columns(&self, connection: &Connection) -> Result<Vec<String>, Error> {
+        self.check()?;
+
+        let sql = match &self.schema {
+            None => "SELECT name FROM pragma_table_info(?1);",
+            Some(_) => "SELECT name FROM pragma_table_info(?1, ?2);"
+        };
+
+        let mut statement = connection.prepare(sql).map_err(|e| Error::from_prepare(sql, e))?;
+
+        let params: Vec<&String> = match &self.schema {
+            None => vec![&self.name],
+            Some(schema) => vec![&self.name, schema]
+        };
+
+        let rows = statement.query_map(params, |row| row.get(0)).map_err(|e| Error::from_execute(sql, e))?;
+
+        rows.collect::<Result<Vec<String>, _>>().map_err(|e| Error::from_execute(sql, e))
+    }
+
+    fn quote(part: &str) -> String {
+        format!("`{}`", part.replace('`', "``"))
+    }
+
+    fn check_part(part: String) -> Result<String, Error> {
+        match Identifier::get_invalid_reason(&part) {
+            Some(reason) => Err(Error::InvalidIdentifier { name: part, reason }),
+            None => Ok(part)
+        }
+    }
+
+    fn get_invalid_reason(part: &str) -> Option<&'static str> {
+        match (part.is_empty(), part.contains('\0')) {
+            (true, _) => Some("Names can't be empty"),
+            (_, true) => Some("Names can't contain NUL characters"),
+            _ => None
+        }
+    }
+
+    /// An unqualified name from a string, checked when it is written into sql.
+    fn from_name(name: String) -> Identifier {
+        Identifier {
+            schema: None,
+            invalid: Identifier::get_invalid_reason(&name),
+            name,
+        }
+    }
+
+    /// Parse one part of a name starting at `start`, returning it and the index after it.
+    fn parse_part(s: &str, chars: &[char], start: usize) -> Result<(String, usize), Error> {
+        let close = match chars.get(start) {
+            Some('"') => '"',
+            Some('`') => '`',
+            Some('[') => ']',
+            _ => {
+                let end = chars[start..].iter().position(|c| *c == '.').map_or(chars.len(), |offset| start + offset);
+                return Ok((chars[start..end].iter().collect(), end));
+            }
+        };
+
+        let mut part = String::new();
+        let mut i = start + 1;
+
+        while i < chars.len() {
+            if chars[i] == close {
+                // A doubled quote is an escaped quote, brackets can't be escaped.
+                match chars.get(i + 1) == Some(&close) && close != ']' {
+                    true => i += 1,
+                    false => return Ok((part, i + 1))
+                }
+            }
+
+            part.push(chars[i]);
+            i += 1;
+        }
+
+        Err(Error::InvalidIdentifier { name: String::from(s), reason: "Unterminated quote" })
+    }
+}
+
+impl fmt::Display for Identifier {
+    /// The unquoted name, i.e. `aux.table`, for messages.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.schema {
+            None => write!(f, "{}", self.name),
+            Some(schema) => write!(f, "{}.{}", schema, self.name)
+        }
+    }
+}
+
+impl From<&str> for Identifier {
+    fn from(name: &str) -> Self {
+        Identifier::from_name(String::from(name))
+    }
+}
+
+impl From<String> for Identifier {
+    fn from(name: String) -> Self {
+        Identifier::from_name(name)
+    }
+}
+
+impl From<&String> for Identifier {
+    fn from(name: &String) -> Self {
+        Identifier::from_name(name.clone())
+    }
+}
+
+impl From<&Identifier> for Identifier {
+    fn from(identifier: &Identifier) -> Self {
+        identifier.clone()
+    }
+}
+
+/// A field read by `DataReader::get`, either a column name, quoted as an `Identifier`,
+/// or a raw SQL expression such as `length(data)`, written into the select as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Column(Identifier),
+    Expr(String),
+}
+
+impl Field {
+    /// A raw SQL expression, it isn't quoted or checked so never build one from user input.
+    pub fn expr<T>(sql: T) -> Field where T : Into<String> {
+        Field::Expr(sql.into())
+    }
+
+    pub fn get_sql(&self) -> Result<String, Error> {
+        match self {
+            Field::Column(identifier) => identifier.get_sql(),
+            Field::Expr(sql) => Ok(sql.clone())
+        }
+    }
+}
+
+impl<T> From<T> for Field where T : Into<Identifier> {
+    fn from(name: T) -> Self {
+        Field::Column(name.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> (Option<String>, String) {
+        let identifier = Identifier::parse(s).unwrap();
+        (identifier.get_schema().map(String::from), String::from(identifier.get_name()))
+    }
+
+    #[test]
+    fn parses_plain_names() {
+        assert_eq!(parse("table"), (None, String::from("table")));
+        assert_eq!(parse("aux.table"), (Some(String::from("aux")), String::from("table")));
+    }
+
+    #[test]
+    fn parses_quoted_parts() {
+        assert_eq!(parse("aux.\"my.table\""), (Some(String::from("aux")), String::from("my.table")));
+        assert_eq!(parse("`a``b`.[c d]"), (Some(String::from("a`b")), String::from("c d")));
+        assert_eq!(parse("\"say \"\"hi\"\"\""), (None, String::from("say \"hi\"")));
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(Identifier::parse("a.b.c").is_err());
+        assert!(Identifier::parse("\"open").is_err());
+        assert!(Identifier::parse("\"a\"b").is_err());
+        assert!(Identifier::parse("").is_err());
+        assert!(Identifier::parse("aux.").is_err());
+    }
+
+    #[test]
+    fn quotes_with_backticks() {
+        let identifier = Identifier::qualified("aux", "my `table`").unwrap();
+
+        assert_eq!(identifier.get_sql().unwrap(), "`aux`.`my ``table```");
+        assert_eq!(identifier.get_name_sql().unwrap(), "`my ``table```");
+    }
+
+    #[test]
+    fn checks_names_from_strings() {
+        assert!(Identifier::from("").get_sql().is_err());
+        assert!(Identifier::from("a\0b").get_name_sql().is_err());
+        assert_eq!(Identifier::from("a.b").get_sql().unwrap(), "`a.b`");
+    }
+
+    #[test]
+    fn writes_fields() {
+        assert_eq!(Field::from("length(data)").get_sql().unwrap(), "`length(data)`");
+        assert_eq!(Field::expr("length(data)").get_sql().unwrap(), "length(data)");
+        assert!(Field::from("").get_sql().is_err());
+    }
+}
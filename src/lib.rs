@@ -1,26 +1,79 @@
 use std::thread::JoinHandle;
-use std::sync::mpsc::{Sender, Receiver, SendError};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use crate::queue::{QueueMessage, WriteQueue, WriteQueueReceiver, QueueMetrics};
+use crate::pool::{ReaderPool, PooledConnection};
 use std::thread;
-use rusqlite::{ToSql, Connection, NO_PARAMS, DatabaseName, Row, MappedRows};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use rusqlite::{Connection, NO_PARAMS, Row, Rows};
+use rusqlite::blob::Blob;
+use std::io::{self, Write};
+use std::fs::File;
 use std::sync::mpsc;
 use rlog::{Logger, Log};
-use std::error::Error;
 use std::path::Path;
-use crate::common::{Query, Queryable, Value, BoxedValue, BlobValue, Criteria, ValueType, BlobRef, Transaction};
-
+use crate::options::ContextOptions;
+use crate::checksum::{ChecksumStorage, HashingWriter};
+use crate::identifier::{Field, Identifier};
+use crate::queries::Select;
+use crate::params::{NamedValue, Params};
+use crate::row::{DynamicRows, FromRow, RowStream};
+use crate::common::{vec_to_optional, Query, ReturnedRow, BoxedValue, Criteria, Transaction};
+
+pub mod blob_store;
+pub mod checksum;
 pub mod common;
+pub mod error;
+pub mod identifier;
+pub mod options;
+pub mod params;
+mod pool;
 pub mod queries;
+pub mod queue;
+pub mod row;
+pub mod struct_values;
+
+pub use crate::error::Error;
 
 
+/// A request for the `DbWriter`.
+/// Each request can optionally carry a reply channel the result will be sent back on.
 pub enum WriteRequest {
-    Query(Query),
-    Transaction(Transaction)
+    Query(Query, Option<Sender<WriteResult>>),
+    Transaction(Transaction, Option<Sender<TransactionResult>>),
+}
+
+/// A `Query` request waiting to be group committed.
+type BatchItem = (Query, Option<Sender<WriteResult>>);
+
+/// How long `Context::shutdown` waits for pending writes before giving up on the `DbWriter`.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The outcome of a successful write.
+#[derive(Debug)]
+pub struct WriteOutcome {
+    pub rows_affected: usize,
+    /// The `last_insert_rowid` of the writer connection after the query was executed.
+    pub last_insert_rowid: i64,
+    /// The rows mapped from the query's `RETURNING` clause, if it has one.
+    pub returned: Vec<ReturnedRow>,
+    /// In a transaction, the `Error::Savepoint` of a `Savepoint` that rolled back on its own.
+    /// The rest of the transaction is still committed.
+    pub rolled_back: Option<Error>,
 }
 
+pub type WriteResult = Result<WriteOutcome, Error>;
+
+/// The outcomes of each query in a transaction, in order.
+pub type TransactionResult = Result<Vec<WriteOutcome>, Error>;
+
 
 /// A `rusq` context. 
 pub struct Context {
     connection_string: String,
+    options: ContextOptions,
+    reader_pool: Arc<ReaderPool>,
     db_writer: DbWriter,
     log: Log,
 }
@@ -28,149 +81,593 @@ pub struct Context {
 // A `DbWriter` is responsible for being the one writer source to the `sqlite` database.
 // It receives `Queries` from `DataWriters` and executes them.
 pub struct DbWriter {
-    handler: JoinHandle<()>,
-    sender: Sender<WriteRequest>,
+    handler: Option<JoinHandle<()>>,
+    queue: WriteQueue,
+    stopped: Receiver<()>,
+    shutdown_sent: bool,
 }
 
 pub struct DataWriter {
-    sender: Sender<WriteRequest>
+    queue: WriteQueue
 }
 
 pub struct DataReader {
-    connection: Connection,
+    connection: PooledConnection,
     logger: Logger,
 }
 
 impl Context {
-    pub fn create(connection_string: String) -> Result<Context, &'static str> {
-        let log = Log::create()?;
-        let connection = Context::create_connection(&connection_string)?;
+    pub fn create(connection_string: String) -> Result<Context, Error> {
+        Context::create_with_options(connection_string, ContextOptions::default())
+    }
 
-        let db_writer = DbWriter::create(connection, log.get_logger())?;
+    pub fn create_with_options(connection_string: String, options: ContextOptions) -> Result<Context, Error> {
+        let log = Log::create().map_err(Error::Logger)?;
+        let connection = Context::create_connection(&connection_string, &options, true)?;
+
+        let writer_connection_string = connection_string.clone();
+        let writer_options = options.clone();
+        let connect = move || Context::create_connection(&writer_connection_string, &writer_options, true);
+
+        let db_writer = DbWriter::create(connection, connect, &options, log.get_logger())?;
+
+        let reader_pool = Arc::new(ReaderPool::create(connection_string.clone(), options.clone()));
 
         Ok(Context {
             connection_string,
+            options,
+            reader_pool,
             db_writer,
             log,
         })
     }
 
-    pub fn get_connection(&self) -> Result<Connection, &'static str> {
-        Context::create_connection(&self.connection_string)
+    /// Open a new reader connection outside of the pool, configured with the context's options.
+    pub fn get_connection(&self) -> Result<Connection, Error> {
+        Context::create_connection(&self.connection_string, &self.options, false)
     }
 
 
-    pub fn get_writer(&self) -> Result<DataWriter, &'static str> {
-        DataWriter::create(self.db_writer.sender.clone())
+    pub fn get_writer(&self) -> Result<DataWriter, Error> {
+        DataWriter::create(self.db_writer.queue.clone())
     }
 
-    pub fn get_reader(&self) -> Result<DataReader, &'static str> {
-        let connection = self.get_connection()?;
+    /// Get a reader with a connection checked out of the reader pool.
+    /// The connection is returned to the pool when the reader is dropped.
+    pub fn get_reader(&self) -> Result<DataReader, Error> {
+        let connection = ReaderPool::get(&self.reader_pool)?;
         let logger = self.log.get_logger();
         DataReader::create(connection, logger)
     }
 
-    fn create_connection(connection_string: &String) -> Result<Connection, &'static str> {
-        match rusqlite::Connection::open(connection_string) {
-            Ok(connection) => Ok(connection),
-            Err(_) => Err("Could not create connection")
-        }
+    /// A snapshot of the `DbWriter` queue, for monitoring backpressure.
+    pub fn get_queue_metrics(&self) -> QueueMetrics {
+        self.db_writer.queue.get_metrics()
+    }
+
+    /// Stop the `DbWriter`, waiting up to `DEFAULT_SHUTDOWN_TIMEOUT` for pending writes to complete.
+    /// This is also called when the context is dropped.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        self.shutdown_with_timeout(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    /// Stop the `DbWriter`, waiting up to `timeout` for pending writes to complete.
+    /// Any `DataWriter` used after this will return `Error::ChannelClosed`.
+    pub fn shutdown_with_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.db_writer.shutdown(timeout)
+    }
+
+    /// Open a connection configured with `options`. Reader connections are read only if `read_only_readers` is set.
+    pub(crate) fn create_connection(connection_string: &String, options: &ContextOptions, writer: bool) -> Result<Connection, Error> {
+        let read_only = !writer && options.read_only_readers;
+
+        let connection = match rusqlite::Connection::open_with_flags(connection_string, options.get_open_flags(read_only)) {
+            Ok(connection) => connection,
+            Err(e) => return Err(Error::Connection { path: connection_string.clone(), source: e })
+        };
+
+        options.apply(&connection, writer)?;
+
+        Ok(connection)
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
     }
 }
 
 impl DbWriter {
-    pub(crate) fn create(mut conn: Connection, logger: Logger) -> Result<DbWriter, &'static str> {
-        logger.log_info(String::from("db_writer"), format!("Starting..."));
+    pub(crate) fn create<C>(mut conn: Connection, connect: C, options: &ContextOptions, logger: Logger) -> Result<DbWriter, Error>
+        where C: Fn() -> Result<Connection, Error> + Send + 'static {
+        logger.log_info(String::from("db_writer"), "Starting...".to_string());
+
+        let (queue, receiver) = queue::create(options.queue_capacity);
+        let (stopped_sender, stopped) = mpsc::channel();
+
+        let max_batch_size = options.max_batch_size;
+        let max_batch_latency = options.max_batch_latency;
+
+        logger.log_success(String::from("db_writer"), "Started successfully".to_string());
+        let handler = thread::spawn(move || {
+            // A request received while filling a batch that can't be part of it.
+            let mut pending: Option<QueueMessage> = None;
+
+            // The loop ends on a shutdown message from `Context::shutdown` or once every sender has been dropped.
+            loop {
+                let request = match pending.take() {
+                    Some(request) => request,
+                    None => match receiver.recv() {
+                        Ok(request) => request,
+                        Err(_) => break
+                    }
+                };
 
-        let (sender, receiver): (Sender<WriteRequest>, Receiver<WriteRequest>) = mpsc::channel();
+                match request {
+                    QueueMessage::Request(WriteRequest::Query(query, reply)) if max_batch_size > 1 => {
+                        let mut batch = vec![(query, reply)];
+                        pending = DbWriter::fill_batch(&receiver, &mut batch, max_batch_size, max_batch_latency);
 
-        logger.log_success(String::from("db_writer"), format!("Started successfully"));
-        let handler = thread::spawn(move || loop {
-            let query = receiver.recv().unwrap();
+                        DbWriter::commit_batch(&mut conn, &connect, &logger, batch);
+                    }
+                    QueueMessage::Request(WriteRequest::Query(query, reply)) => {
+                        let result = DbWriter::supervise(&mut conn, &connect, &logger, |conn| {
+                            DbWriter::handle_query(conn, &logger, query)
+                        });
 
-            match query {
-                WriteRequest::Query(query) => {
-                    logger.log_info(String::from("db_writer"), format!("Query received, type: `{}`", query.get_type_name()));
-                    logger.log_debug(String::from("db_writer"), format!("Sql: `{}`", query.get_raw_sql()));
+                        DbWriter::reply(&logger, reply, result);
+                    }
+                    QueueMessage::Request(WriteRequest::Transaction(transaction, reply)) => {
+                        logger.log_info(String::from("db_writer"), String::from("Transaction received"));
 
-                    match query.execute(&conn) {
-                        Ok(_) => {
-                            logger.log_success(String::from("db_writer"), format!("Query executed successfully."));
-                        }
-                        Err(e) => {
-                            logger.log_error(String::from("db_writer"), format!("Could not execute query, error: `{}`", e));
+                        let result = DbWriter::supervise(&mut conn, &connect, &logger, |conn| {
+                            DbWriter::handle_transaction(conn, &logger, transaction)
+                        });
+
+                        DbWriter::reply(&logger, reply, result);
+                    }
+                    QueueMessage::Shutdown => break
+                }
+            }
+
+            logger.log_info(String::from("db_writer"), String::from("Shutting down..."));
+
+            match conn.close() {
+                Ok(_) => logger.log_success(String::from("db_writer"), String::from("Connection closed.")),
+                Err((_, e)) => logger.log_error(String::from("db_writer"), format!("Could not close connection, error: `{}`", e))
+            }
+
+            let _ = stopped_sender.send(());
+        });
+
+        Ok(DbWriter {
+            handler: Some(handler),
+            queue,
+            stopped,
+            shutdown_sent: false,
+        })
+    }
+
+    /// Stop the thread, waiting up to `timeout` for the shutdown message to be queued and every request before it handled.
+    /// On `Error::Timeout` the thread is still running, and calling this again carries on waiting.
+    pub(crate) fn shutdown(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.handler.is_none() {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        if !self.shutdown_sent {
+            match self.queue.send_shutdown(timeout) {
+                // If the send fails the thread has already stopped, so there is nothing to drain.
+                Ok(_) | Err(Error::ChannelClosed) => self.shutdown_sent = true,
+                Err(e) => return Err(e)
+            }
+        }
+
+        match self.stopped.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => {
+                if let Some(handler) = self.handler.take() {
+                    let _ = handler.join();
+                }
+
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout)
+        }
+    }
+
+    /// Run `f` against the connection, catching any panic so one bad query doesn't stop all writes.
+    /// After a panic the connection is reopened, as it might have been left mid-statement.
+    fn supervise<T, F>(conn: &mut Connection, connect: &dyn Fn() -> Result<Connection, Error>, logger: &Logger, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Connection) -> Result<T, Error> {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(conn))) {
+            Ok(result) => result,
+            Err(_) => {
+                logger.log_error(String::from("db_writer"), String::from("Query panicked. Restarting writer connection."));
+
+                match connect() {
+                    Ok(new_conn) => *conn = new_conn,
+                    Err(e) => logger.log_error(String::from("db_writer"), format!("Could not reopen connection, error: `{}`", e))
+                }
+
+                Err(Error::WriterPanicked)
+            }
+        }
+    }
+
+    /// Add queued `Query` requests to the batch until it is full or `max_latency` has passed.
+    /// Any other request ends the batch early and is returned so it can be handled next.
+    fn fill_batch(receiver: &WriteQueueReceiver, batch: &mut Vec<BatchItem>, max_batch_size: usize, max_latency: Duration) -> Option<QueueMessage> {
+        let deadline = Instant::now() + max_latency;
+
+        while batch.len() < max_batch_size {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(QueueMessage::Request(WriteRequest::Query(query, reply))) => batch.push((query, reply)),
+                Ok(message) => return Some(message),
+                Err(_) => break
+            }
+        }
+
+        None
+    }
+
+    fn commit_batch(conn: &mut Connection, connect: &dyn Fn() -> Result<Connection, Error>, logger: &Logger, batch: Vec<BatchItem>) {
+        logger.log_info(String::from("db_writer"), format!("Batch received, size: {}", batch.len()));
+
+        let (queries, replies): (Vec<Query>, Vec<Option<Sender<WriteResult>>>) = batch.into_iter().unzip();
+
+        let results = DbWriter::supervise(conn, connect, logger, |conn| {
+            DbWriter::handle_batch(conn, connect, logger, &queries)
+        });
+
+        match results {
+            Ok(results) => {
+                for (reply, result) in replies.into_iter().zip(results) {
+                    DbWriter::reply(logger, reply, result);
+                }
+            }
+            Err(e) => {
+                logger.log_error(String::from("db_writer"), format!("Could not commit batch, error: `{}`", e));
+
+                for reply in replies {
+                    DbWriter::reply(logger, reply, Err(e.for_batch()));
+                }
+            }
+        }
+    }
+
+    /// Execute the batch in a single transaction, with each query in its own savepoint.
+    /// A query that ends the transaction, i.e. `INSERT OR ROLLBACK`, or panics fails on its own
+    /// and the rest of the batch is run again in a new transaction.
+    /// The outer error is only returned if the transaction itself fails, in which case nothing is committed.
+    fn handle_batch(conn: &mut Connection, connect: &dyn Fn() -> Result<Connection, Error>, logger: &Logger, queries: &[Query]) -> Result<Vec<WriteResult>, Error> {
+        let mut results: Vec<Option<WriteResult>> = queries.iter().map(|_| None).collect();
+
+        // Each retry has one more result, so this ends after at most one attempt per query.
+        while !DbWriter::try_batch(conn, connect, logger, queries, &mut results)? {
+            logger.log_info(String::from("db_writer"), String::from("Retrying the rest of the batch in a new transaction."));
+        }
+
+        // Every query has a result once the batch is committed.
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Run the queries without a result yet in one transaction. Returns `false` if a query ended the transaction,
+    /// in which case the queries after it have to be run again.
+    fn try_batch(conn: &mut Connection, connect: &dyn Fn() -> Result<Connection, Error>, logger: &Logger, queries: &[Query], results: &mut [Option<WriteResult>]) -> Result<bool, Error> {
+        conn.execute_batch("BEGIN;").map_err(|e| Error::from_execute("BEGIN;", e))?;
+
+        let mut attempt = Vec::new();
+
+        for (index, query) in queries.iter().enumerate() {
+            if results[index].is_some() {
+                continue;
+            }
+
+            logger.log_debug(String::from("db_writer"), format!("Type: `{}`", query.get_type_name()));
+            logger.log_debug(String::from("db_writer"), format!("Sql: `{}`", query.get_raw_sql()));
+
+            if let Err(e) = conn.execute_batch("SAVEPOINT rusq_batch;") {
+                DbWriter::rollback_batch(conn);
+                return Err(Error::from_execute("SAVEPOINT rusq_batch;", e));
+            }
+
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| query.execute_returning(conn))) {
+                Ok(result) => result,
+                Err(_) => {
+                    logger.log_error(String::from("db_writer"), format!("Query {} in the batch panicked.", index));
+                    results[index] = Some(Err(Error::WriterPanicked));
+
+                    // The connection might have been left mid-statement, so reopen it if it can't be rolled back.
+                    if !DbWriter::rollback_batch(conn) {
+                        match connect() {
+                            Ok(new_conn) => *conn = new_conn,
+                            Err(e) => logger.log_error(String::from("db_writer"), format!("Could not reopen connection, error: `{}`", e))
                         }
                     }
+
+                    return Ok(false);
                 }
-                WriteRequest::Transaction(transaction) => {
-                    logger.log_info(String::from("db_writer"), String::from("Transaction received"));
-
-                    let tx = conn.transaction().unwrap();
-
-                    for query in transaction {
-                        logger.log_debug(String::from("db_writer"), format!("Type: `{}`", query.get_type_name()));
-                        logger.log_debug(String::from("db_writer"), format!("Sql: `{}`", query.get_raw_sql()));
-                        match query.execute(&tx) {
-                            Ok(_) => {
-                                logger.log_success(String::from("db_writer"), format!("Query executed successfully."));
-                            }
-                            Err(e) => {
-                                logger.log_error(String::from("db_writer"), format!("Could not execute query, error: `{}`", e));
-                            }
+            };
+
+            // The query ended the whole transaction, so the savepoints are gone as well.
+            // If it failed the transaction was rolled back, i.e. by `INSERT OR ROLLBACK`, and the earlier queries have to run again.
+            // If it succeeded it is taken to be a `COMMIT`, so the earlier queries keep their results.
+            if conn.is_autocommit() {
+                let error = match result {
+                    Ok(_) => {
+                        for (index, result) in attempt {
+                            results[index] = Some(result);
                         }
+
+                        Error::InvalidQuery("A query in a batch can't end the transaction")
                     }
+                    Err(e) => e
+                };
+
+                logger.log_error(String::from("db_writer"), format!("Query {} ended the batch transaction, error: `{}`", index, error));
+                results[index] = Some(Err(error));
+                return Ok(false);
+            }
+
+            let sql = match &result {
+                Ok(_) => "RELEASE rusq_batch;",
+                Err(_) => "ROLLBACK TO rusq_batch; RELEASE rusq_batch;"
+            };
 
-                    tx.commit();
+            if let Err(e) = conn.execute_batch(sql) {
+                DbWriter::rollback_batch(conn);
+                return Err(Error::from_execute(sql, e));
+            }
+
+            match result {
+                Ok((rows_affected, returned)) => attempt.push((index, Ok(WriteOutcome {
+                    rows_affected,
+                    last_insert_rowid: conn.last_insert_rowid(),
+                    returned,
+                    rolled_back: None,
+                }))),
+                Err(e) => {
+                    logger.log_error(String::from("db_writer"), format!("Could not execute query, error: `{}`", e));
+                    attempt.push((index, Err(e)));
                 }
             }
-        });
+        }
 
-        Ok(DbWriter {
-            handler,
-            sender,
-        })
+        if let Err(e) = conn.execute_batch("COMMIT;") {
+            DbWriter::rollback_batch(conn);
+            return Err(Error::from_execute("COMMIT;", e));
+        }
+
+        logger.log_success(String::from("db_writer"), "Batch committed successfully.".to_string());
+
+        for (index, result) in attempt {
+            results[index] = Some(result);
+        }
+
+        Ok(true)
+    }
+
+    /// Roll back the batch transaction if it is still open, returning `false` if that failed.
+    fn rollback_batch(conn: &Connection) -> bool {
+        conn.is_autocommit() || conn.execute_batch("ROLLBACK;").is_ok()
+    }
+
+    fn handle_query(conn: &mut Connection, logger: &Logger, query: Query) -> WriteResult {
+        logger.log_info(String::from("db_writer"), format!("Query received, type: `{}`", query.get_type_name()));
+        logger.log_debug(String::from("db_writer"), format!("Sql: `{}`", query.get_raw_sql()));
+
+        match query.execute_returning(conn) {
+            Ok((rows_affected, returned)) => {
+                logger.log_success(String::from("db_writer"), "Query executed successfully.".to_string());
+                Ok(WriteOutcome {
+                    rows_affected,
+                    last_insert_rowid: conn.last_insert_rowid(),
+                    returned,
+                    rolled_back: None,
+                })
+            }
+            Err(e) => {
+                logger.log_error(String::from("db_writer"), format!("Could not execute query, error: `{}`", e));
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_transaction(conn: &mut Connection, logger: &Logger, transaction: Transaction) -> TransactionResult {
+        let tx = match conn.transaction() {
+            Ok(tx) => tx,
+            Err(e) => {
+                logger.log_error(String::from("db_writer"), String::from("Could not start transaction."));
+                return Err(Error::from_execute("BEGIN", e));
+            }
+        };
+
+        let mut outcomes = Vec::new();
+
+        for (index, query) in transaction.iter().enumerate() {
+            logger.log_debug(String::from("db_writer"), format!("Type: `{}`", query.get_type_name()));
+            logger.log_debug(String::from("db_writer"), format!("Sql: `{}`", query.get_raw_sql()));
+            match query.execute_returning(&tx) {
+                Ok((rows_affected, returned)) => {
+                    logger.log_success(String::from("db_writer"), "Query executed successfully.".to_string());
+                    outcomes.push(WriteOutcome {
+                        rows_affected,
+                        last_insert_rowid: tx.last_insert_rowid(),
+                        returned,
+                        rolled_back: None,
+                    });
+                }
+                Err(e @ Error::Savepoint { .. }) => {
+                    logger.log_error(String::from("db_writer"), format!("Savepoint {} rolled back, error: `{}`", index, e));
+
+                    // The savepoint has already undone its own queries, the transaction carries on.
+                    outcomes.push(WriteOutcome {
+                        rows_affected: 0,
+                        last_insert_rowid: tx.last_insert_rowid(),
+                        returned: Vec::new(),
+                        rolled_back: Some(e),
+                    });
+                }
+                Err(e) => {
+                    logger.log_error(String::from("db_writer"), format!("Could not execute query {}, error: `{}`. Rolling back transaction.", index, e));
+
+                    if tx.rollback().is_err() {
+                        logger.log_error(String::from("db_writer"), String::from("Could not roll back transaction."));
+                    }
+
+                    return Err(Error::Transaction { index, source: Box::new(e) });
+                }
+            }
+        }
+
+        if let Err(e) = tx.commit() {
+            logger.log_error(String::from("db_writer"), String::from("Could not commit transaction."));
+            return Err(Error::from_execute("COMMIT", e));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Send a result back to the requester, if they asked for one.
+    fn reply<T>(logger: &Logger, reply: Option<Sender<T>>, result: T) {
+        if let Some(sender) = reply {
+            if sender.send(result).is_err() {
+                logger.log_error(String::from("db_writer"), String::from("Could not send result, the requester is no longer listening."));
+            }
+        }
     }
+}
+
+impl WriteOutcome {
+    /// Take the returned rows as the type the `Returning` mapper produced.
+    /// Fails with `Error::ReturnedType`, leaving the rows in place, if the mapper produced a different type.
+    pub fn take_returned<T>(&mut self) -> Result<Vec<T>, Error> where T: 'static {
+        if !self.returned.iter().all(|row| row.is::<T>()) {
+            return Err(Error::ReturnedType(std::any::type_name::<T>()));
+        }
+
+        let rows = std::mem::take(&mut self.returned);
 
-    pub fn get_sender(&self) -> Sender<WriteRequest> {
-        self.sender.clone()
+        Ok(rows.into_iter().filter_map(|row| row.downcast::<T>().ok()).map(|row| *row).collect())
     }
 }
 
 impl DataWriter {
-    pub(crate) fn create(sender: Sender<WriteRequest>) -> Result<DataWriter, &'static str> {
+    pub(crate) fn create(queue: WriteQueue) -> Result<DataWriter, Error> {
         Ok(DataWriter {
-            sender
+            queue
         })
     }
 
-    pub fn post(&self, request: WriteRequest) -> Result<(), &'static str> {
-        match self.sender.send(request) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Could not send query. Check `db_writer` channel is not closed.")
-        }
+    /// Post a request, blocking while the queue is full if it is bounded.
+    pub fn post(&self, request: WriteRequest) -> Result<(), Error> {
+        self.queue.send(request)
+    }
+
+    /// Post a request without blocking, returning `Error::QueueFull` if the queue is full.
+    pub fn try_post(&self, request: WriteRequest) -> Result<(), Error> {
+        self.queue.try_send(request)
     }
-    
-    pub fn post_query(&self, query: Query) -> Result<(), &'static str> {
-        self.post(WriteRequest::Query(query))
+
+    /// Post a request, waiting up to `timeout` for space in the queue before returning `Error::Timeout`.
+    pub fn post_timeout(&self, request: WriteRequest, timeout: Duration) -> Result<(), Error> {
+        self.queue.send_timeout(request, timeout)
+    }
+
+    pub fn post_query(&self, query: Query) -> Result<(), Error> {
+        self.post(WriteRequest::Query(query, None))
+    }
+
+    pub fn post_transaction(&self, transaction: Transaction) -> Result<(), Error> {
+        self.post(WriteRequest::Transaction(transaction, None))
+    }
+
+    /// Post a query and block until the `db_writer` has executed it.
+    pub fn execute_query(&self, query: Query) -> WriteResult {
+        let (sender, receiver) = mpsc::channel();
+
+        self.post(WriteRequest::Query(query, Some(sender)))?;
+
+        receiver.recv().unwrap_or(Err(Error::ChannelClosed))
     }
 
-    pub fn post_transaction(&self, transaction: Transaction) -> Result<(), &'static str> {
-        self.post(WriteRequest::Transaction(transaction))
+    /// Post a transaction and block until the `db_writer` has executed it.
+    pub fn execute_transaction(&self, transaction: Transaction) -> TransactionResult {
+        let (sender, receiver) = mpsc::channel();
+
+        self.post(WriteRequest::Transaction(transaction, Some(sender)))?;
+
+        receiver.recv().unwrap_or(Err(Error::ChannelClosed))
+    }
+
+    /// Delete unreferenced blobs from the blob store, blocking until the `db_writer` has run it.
+    /// `rows_affected` is the number of blobs deleted.
+    pub fn collect_blob_garbage(&self) -> WriteResult {
+        self.execute_query(blob_store::CollectBlobGarbage::create()?)
     }
 }
 
 impl DataReader {
-    pub(crate) fn create(connection: Connection, logger: Logger) -> Result<DataReader, &'static str> {
+    pub(crate) fn create(connection: PooledConnection, logger: Logger) -> Result<DataReader, Error> {
         Ok(DataReader {
             connection,
             logger,
         })
     }
 
-    pub fn get<T, F>(&self, table_name: &str, field_names: Vec<&str>, criteria: Option<Criteria>, mapper: F)
-                     -> Result<Vec<T>, &'static str>
-        where F: FnMut(&Row<'_>) -> Result<T, std::io::Error> {
-        let fields = field_names.join(", ");
+    /// Select the fields from a table. The names are quoted, so use `Identifier::qualified`
+    /// for a table in an attached database rather than writing `aux.table`.
+    /// Field names are quoted as columns, pass `Field::expr` for an expression such as `length(data)`.
+    pub fn get<T, F, I, J>(&self, table_name: I, field_names: Vec<J>, criteria: Option<Criteria>, mapper: F)
+                     -> Result<Vec<T>, Error>
+        where F: FnMut(&Row<'_>) -> Result<T, std::io::Error>, I: Into<Identifier>, J: Into<Field> {
+        let fields = field_names.into_iter().map(|field| field.into().get_sql()).collect::<Result<Vec<String>, Error>>()?;
+        let (sql, values) = DataReader::get_select_sql(table_name.into(), fields.join(", "), criteria)?;
+
+        self.handle_get(sql, values, mapper)
+    }
+
+    /// Prepare a select whose rows are mapped lazily, one at a time, rather than collected,
+    /// i.e. for scanning large tables. Call `RowStream::iter` with a mapper to read the rows.
+    pub fn stream<I, J>(&self, table_name: I, field_names: Vec<J>, criteria: Option<Criteria>) -> Result<RowStream<'_>, Error> where I: Into<Identifier>, J: Into<Field> {
+        let fields = field_names.into_iter().map(|field| field.into().get_sql()).collect::<Result<Vec<String>, Error>>()?;
+        let (sql, values) = DataReader::get_select_sql(table_name.into(), fields.join(", "), criteria)?;
+
+        self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
+        RowStream::create(&self.connection, sql, values.unwrap_or_default())
+    }
+
+    /// Select rows without a mapper, as column name and value pairs along with the columns' metadata.
+    /// If `field_names` is empty every column is selected.
+    pub fn get_dynamic<I, J>(&self, table_name: I, field_names: Vec<J>, criteria: Option<Criteria>) -> Result<DynamicRows, Error> where I: Into<Identifier>, J: Into<Field> {
+        let fields = match field_names.is_empty() {
+            true => String::from("*"),
+            false => {
+                let fields = field_names.into_iter().map(|field| field.into().get_sql()).collect::<Result<Vec<String>, Error>>()?;
+                fields.join(", ")
+            }
+        };
+
+        let (sql, values) = DataReader::get_select_sql(table_name.into(), fields, criteria)?;
+
+        self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
+        row::query_dynamic(&self.connection, &sql, values.unwrap_or_default())
+    }
+
+    /// Run raw sql with `:name` placeholders without a mapper, i.e. for ad-hoc queries.
+    pub fn query_dynamic<T>(&self, sql: T, values: Vec<NamedValue>) -> Result<DynamicRows, Error> where T: Into<String> {
+        let mut params = Params::create();
+        let sql = params.bind_named(&sql.into(), values)?;
+
+        self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
+        row::query_dynamic(&self.connection, &sql, params.finish()?)
+    }
+
+    fn get_select_sql(table_name: Identifier, fields: String, criteria: Option<Criteria>) -> Result<(String, Option<Vec<BoxedValue>>), Error> {
+        let table_name = table_name.get_sql()?;
 
         let (sql, values) = match criteria {
             None => {
@@ -180,7 +677,7 @@ impl DataReader {
                 (sql, None)
             }
             Some(c) => {
-                let (crit_string, values) = Criteria::handle(c);
+                let (crit_string, values) = Criteria::handle(c)?;
 
 
                 let sql = format!("SELECT {} FROM {} WHERE {}", fields, table_name, crit_string);
@@ -188,10 +685,129 @@ impl DataReader {
                 (sql, values)
             }
         };
-        self.handle_get(sql, values, mapper)
+
+        Ok((sql, values))
+    }
+
+    /// Select the fields of `T` and map each row with `FromRow`,
+    /// i.e. `reader.get_as::<Person, _>("people", None)` for a struct that derives `Deserialize`.
+    pub fn get_as<T, I>(&self, table_name: I, criteria: Option<Criteria>) -> Result<Vec<T>, Error> where T: FromRow, I: Into<Identifier> {
+        self.get(table_name, T::get_field_names()?, criteria, |row| T::from_row(row))
+    }
+
+    /// Run a `Select`, mapping each row with `mapper`.
+    pub fn select<T, F>(&self, select: Select, mapper: F) -> Result<Vec<T>, Error> where F: FnMut(&Row<'_>) -> Result<T, std::io::Error> {
+        let (sql, values) = select.build()?;
+        self.handle_get(sql, vec_to_optional(values), mapper)
+    }
+
+    /// Run a `Select` without a mapper, see `get_dynamic`.
+    pub fn select_dynamic(&self, select: Select) -> Result<DynamicRows, Error> {
+        let (sql, values) = select.build()?;
+
+        self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
+        row::query_dynamic(&self.connection, &sql, values)
+    }
+
+    /// Prepare a `Select` whose rows are mapped lazily, see `stream`.
+    pub fn stream_select(&self, select: Select) -> Result<RowStream<'_>, Error> {
+        let (sql, values) = select.build()?;
+
+        self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
+        RowStream::create(&self.connection, sql, values)
+    }
+
+    /// Check the table and fields exist in the live schema,
+    /// i.e. before using names that come from configuration.
+    pub fn check_identifiers(&self, table_name: &Identifier, field_names: &[Identifier]) -> Result<(), Error> {
+        table_name.check_table(&self.connection)?;
+
+        for field in field_names {
+            field.check_column(table_name, &self.connection)?;
+        }
+
+        Ok(())
+    }
+
+    /// Open a blob for streaming reads. The handle implements `Read` and `Seek`,
+    /// so large blobs can be read in chunks rather than loaded into memory.
+    pub fn open_blob<T, U>(&self, table_name: T, field_name: U, row_id: i64) -> Result<Blob<'_>, Error> where T: Into<Identifier>, U: Into<Identifier> {
+        let table_name = table_name.into();
+        let field_name = field_name.into();
+
+        self.connection.blob_open(table_name.get_database_name(), table_name.get_name(), field_name.get_name(), row_id, true)
+            .map_err(|e| Error::from_blob(&table_name, &field_name, e))
+    }
+
+    /// Stream a blob into `writer`, returning the number of bytes copied.
+    pub fn copy_blob<T, U, W>(&self, table_name: T, field_name: U, row_id: i64, writer: &mut W) -> Result<u64, Error> where T: Into<Identifier>, U: Into<Identifier>, W: Write {
+        let table_name = table_name.into();
+        let field_name = field_name.into();
+        let mut blob = self.open_blob(&table_name, &field_name, row_id)?;
+
+        io::copy(&mut blob, writer).map_err(|e| Error::from_blob(&table_name, &field_name, e))
+    }
+
+    /// Stream a blob into a file at `path`, creating or truncating it.
+    pub fn copy_blob_to_file<T, U, P>(&self, table_name: T, field_name: U, row_id: i64, path: P) -> Result<u64, Error> where T: Into<Identifier>, U: Into<Identifier>, P: AsRef<Path> {
+        let table_name = table_name.into();
+        let field_name = field_name.into();
+        let mut file = File::create(path).map_err(|e| Error::from_blob(&table_name, &field_name, e))?;
+
+        let copied = self.copy_blob(&table_name, &field_name, row_id, &mut file)?;
+
+        file.flush().map_err(|e| Error::from_blob(&table_name, &field_name, e))?;
+
+        Ok(copied)
+    }
+
+    /// Stream a blob into `writer` while computing its SHA-256, then compare it with the stored checksum.
+    /// The data is written before it can be verified, so on `Error::ChecksumMismatch` anything written should be discarded.
+    pub fn copy_blob_verified<T, U, W>(&self, table_name: T, field_name: U, row_id: i64, storage: &ChecksumStorage, writer: &mut W) -> Result<u64, Error> where T: Into<Identifier>, U: Into<Identifier>, W: Write {
+        let table_name = table_name.into();
+        let field_name = field_name.into();
+        let mut hashing_writer = HashingWriter::create(writer);
+
+        let copied = self.copy_blob(&table_name, &field_name, row_id, &mut hashing_writer)?;
+
+        storage.verify(&self.connection, &table_name, &field_name, row_id, hashing_writer.finish())?;
+
+        Ok(copied)
+    }
+
+    /// Check a blob against its stored checksum without keeping the data.
+    pub fn verify_blob<T, U>(&self, table_name: T, field_name: U, row_id: i64, storage: &ChecksumStorage) -> Result<(), Error> where T: Into<Identifier>, U: Into<Identifier> {
+        self.copy_blob_verified(table_name, field_name, row_id, storage, &mut io::sink()).map(|_| ())
+    }
+
+    /// Open a blob in the blob store by its hash, returning `Error::UnexpectedRowCount` if it isn't stored.
+    pub fn open_stored_blob(&self, hash: &str) -> Result<Blob<'_>, Error> {
+        let row_id = blob_store::get_row_id(&self.connection, hash)?;
+
+        self.open_blob(blob_store::BLOB_TABLE, "data", row_id)
     }
 
-    fn handle_get<T, F>(&self, sql: String, params: Option<Vec<BoxedValue>>, mapper: F) -> Result<Vec<T>, &'static str> where F: FnMut(&Row<'_>) -> Result<T, std::io::Error> {
+    /// Stream a blob in the blob store into `writer`, verifying it against its hash.
+    pub fn copy_stored_blob<W>(&self, hash: &str, writer: &mut W) -> Result<u64, Error> where W: Write {
+        let row_id = blob_store::get_row_id(&self.connection, hash)?;
+        let mut hashing_writer = HashingWriter::create(writer);
+
+        let copied = self.copy_blob(blob_store::BLOB_TABLE, "data", row_id, &mut hashing_writer)?;
+        let actual = hashing_writer.finish();
+
+        match actual == hash {
+            true => Ok(copied),
+            false => Err(Error::ChecksumMismatch {
+                table: String::from(blob_store::BLOB_TABLE),
+                field: String::from("data"),
+                row_id,
+                expected: Some(String::from(hash)),
+                actual,
+            })
+        }
+    }
+
+    fn handle_get<T, F>(&self, sql: String, params: Option<Vec<BoxedValue>>, mapper: F) -> Result<Vec<T>, Error> where F: FnMut(&Row<'_>) -> Result<T, std::io::Error> {
 
         self.logger.log_debug(String::from("db_reader"), format!("Sql: {}", sql));
         match params {
@@ -203,44 +819,38 @@ impl DataReader {
     pub fn query_no_params<T, F>(
         connection: &Connection,
         sql: String,
-        mut f: F,
-    ) -> Result<Vec<T>, &'static str> where
-    //P: IntoIterator,
-    //P::Item: ToSql,
+        f: F,
+    ) -> Result<Vec<T>, Error> where
         F: FnMut(&Row<'_>) -> Result<T, std::io::Error>, {
-        let mut stmt = connection.prepare(sql.as_str()).unwrap();
-        let mut result: Vec<T> = Vec::new();
-
+        let mut stmt = connection.prepare(sql.as_str()).map_err(|e| Error::from_prepare(&sql, e))?;
 
-        let rows = stmt.query_map(NO_PARAMS, |row| { Ok(f(row).unwrap()) }).unwrap();
-        //let r = stmt.query_map(NO_PARAMS, |row| { Ok(f(row).unwrap()) }).unwrap();
+        let rows = stmt.query(NO_PARAMS).map_err(|e| Error::from_execute(&sql, e))?;
 
-        for row in rows {
-            result.push(row.unwrap());
-        }
-
-        Ok(result)
+        DataReader::collect_rows(&sql, rows, f)
     }
 
     pub fn query_with_params<T, F>(
         connection: &Connection,
         sql: String,
         params: Vec<BoxedValue>,
-        mut f: F,
-    ) -> Result<Vec<T>, &'static str> where
-    //P: IntoIterator,
-    //P::Item: ToSql,
+        f: F,
+    ) -> Result<Vec<T>, Error> where
         F: FnMut(&Row<'_>) -> Result<T, std::io::Error>, {
-        let mut stmt = connection.prepare(sql.as_str()).unwrap();
-        let mut result: Vec<T> = Vec::new();
+        let mut stmt = connection.prepare(sql.as_str()).map_err(|e| Error::from_prepare(&sql, e))?;
 
+        let rows = stmt.query(params).map_err(|e| Error::from_execute(&sql, e))?;
 
-        let rows = stmt.query_map(params, |row| { Ok(f(row).unwrap()) }).unwrap();
+        DataReader::collect_rows(&sql, rows, f)
+    }
 
-        //let r = stmt.query_map(NO_PARAMS, |row| { Ok(f(row).unwrap()) }).unwrap();
+    fn collect_rows<T, F>(sql: &str, mut rows: Rows<'_>, mut f: F) -> Result<Vec<T>, Error> where F: FnMut(&Row<'_>) -> Result<T, std::io::Error> {
+        let mut result: Vec<T> = Vec::new();
 
-        for row in rows {
-            result.push(row.unwrap());
+        while let Some(row) = rows.next().map_err(|e| Error::from_execute(sql, e))? {
+            match f(row) {
+                Ok(item) => result.push(item),
+                Err(e) => return Err(Error::Mapping { sql: String::from(sql), source: e })
+            }
         }
 
         Ok(result)
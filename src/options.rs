@@ -0,0 +1,211 @@
+use std::time::Duration;
+use rusqlite::{Connection, OpenFlags};
+use crate::error::Error;
+
+/// Options used when creating a `Context`.
+/// Connection settings are applied to the writer connection and every reader connection.
+#[derive(Debug, Clone)]
+pub struct ContextOptions {
+    pub(crate) max_batch_size: usize,
+    pub(crate) max_batch_latency: Duration,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) journal_mode: Option<JournalMode>,
+    pub(crate) synchronous: Option<Synchronous>,
+    pub(crate) busy_timeout: Option<Duration>,
+    pub(crate) foreign_keys: Option<bool>,
+    pub(crate) cache_size: Option<i64>,
+    pub(crate) mmap_size: Option<i64>,
+    pub(crate) read_only_readers: bool,
+    pub(crate) reader_pool_size: Option<usize>,
+    pub(crate) reader_checkout_timeout: Duration,
+    pub(crate) pragmas: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra
+}
+
+impl ContextOptions {
+    pub fn create() -> ContextOptions {
+        ContextOptions {
+            max_batch_size: 1,
+            max_batch_latency: Duration::from_millis(0),
+            queue_capacity: None,
+            journal_mode: None,
+            synchronous: None,
+            busy_timeout: None,
+            foreign_keys: None,
+            cache_size: None,
+            mmap_size: None,
+            read_only_readers: false,
+            reader_pool_size: None,
+            reader_checkout_timeout: Duration::from_secs(30),
+            pragmas: Vec::new(),
+        }
+    }
+
+    /// Group commit `Query` requests in the `DbWriter`.
+    /// Whatever is queued is executed in a single transaction, up to `max_batch_size` requests.
+    /// After the first request the writer waits up to `max_latency` for more before committing.
+    /// Each request runs in its own savepoint, so one failing doesn't affect the rest of the batch.
+    pub fn batch(mut self, max_batch_size: usize, max_latency: Duration) -> ContextOptions {
+        self.max_batch_size = max_batch_size.max(1);
+        self.max_batch_latency = max_latency;
+        self
+    }
+
+    /// Bound the `DbWriter` queue to `capacity` requests, so posting applies backpressure
+    /// instead of letting memory grow when writes can't keep up.
+    /// By default the queue is unbounded.
+    pub fn queue_capacity(mut self, capacity: usize) -> ContextOptions {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Set `PRAGMA journal_mode`. `JournalMode::Wal` lets readers run alongside the writer.
+    /// The journal mode is persistent, so it is only set by the writer connection.
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> ContextOptions {
+        self.journal_mode = Some(journal_mode);
+        self
+    }
+
+    pub fn synchronous(mut self, synchronous: Synchronous) -> ContextOptions {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    /// How long a connection waits on a locked database before returning `SQLITE_BUSY`.
+    pub fn busy_timeout(mut self, timeout: Duration) -> ContextOptions {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    pub fn foreign_keys(mut self, enabled: bool) -> ContextOptions {
+        self.foreign_keys = Some(enabled);
+        self
+    }
+
+    /// Set `PRAGMA cache_size`. Positive values are pages, negative values are KiB.
+    pub fn cache_size(mut self, cache_size: i64) -> ContextOptions {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    /// Set `PRAGMA mmap_size` in bytes.
+    pub fn mmap_size(mut self, mmap_size: i64) -> ContextOptions {
+        self.mmap_size = Some(mmap_size);
+        self
+    }
+
+    /// Open reader connections with `SQLITE_OPEN_READ_ONLY`.
+    pub fn read_only_readers(mut self, read_only: bool) -> ContextOptions {
+        self.read_only_readers = read_only;
+        self
+    }
+
+    /// Limit the reader pool to `max_size` connections.
+    /// Once they are all checked out `Context::get_reader` waits up to `checkout_timeout`
+    /// for one to be returned before failing with `Error::Timeout`.
+    /// By default the pool is unbounded and only reuses idle connections.
+    pub fn reader_pool(mut self, max_size: usize, checkout_timeout: Duration) -> ContextOptions {
+        self.reader_pool_size = Some(max_size);
+        self.reader_checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// Set any other pragma, applied after the named options in the order they were added.
+    pub fn pragma<T, U>(mut self, name: T, value: U) -> ContextOptions where T : Into<String>, U : Into<String> {
+        self.pragmas.push((name.into(), value.into()));
+        self
+    }
+
+    pub(crate) fn get_open_flags(&self, read_only: bool) -> OpenFlags {
+        match read_only {
+            true => OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            false => OpenFlags::default()
+        }
+    }
+
+    /// Apply the connection settings. `writer` should be true for the `DbWriter` connection.
+    pub(crate) fn apply(&self, connection: &Connection, writer: bool) -> Result<(), Error> {
+        if let Some(timeout) = self.busy_timeout {
+            connection.busy_timeout(timeout).map_err(|e| Error::from_execute("PRAGMA busy_timeout", e))?;
+        }
+
+        let mut pragmas = Vec::new();
+
+        if let (Some(journal_mode), true) = (self.journal_mode, writer) {
+            pragmas.push(format!("PRAGMA journal_mode = {};", journal_mode.get_sql()));
+        }
+
+        if let Some(synchronous) = self.synchronous {
+            pragmas.push(format!("PRAGMA synchronous = {};", synchronous.get_sql()));
+        }
+
+        if let Some(foreign_keys) = self.foreign_keys {
+            pragmas.push(format!("PRAGMA foreign_keys = {};", if foreign_keys { "ON" } else { "OFF" }));
+        }
+
+        if let Some(cache_size) = self.cache_size {
+            pragmas.push(format!("PRAGMA cache_size = {};", cache_size));
+        }
+
+        if let Some(mmap_size) = self.mmap_size {
+            pragmas.push(format!("PRAGMA mmap_size = {};", mmap_size));
+        }
+
+        for (name, value) in &self.pragmas {
+            pragmas.push(format!("PRAGMA {} = {};", name, value));
+        }
+
+        for sql in pragmas {
+            connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl JournalMode {
+    pub fn get_sql(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF"
+        }
+    }
+}
+
+impl Synchronous {
+    pub fn get_sql(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA"
+        }
+    }
+}
+
+impl Default for ContextOptions {
+    fn default() -> Self {
+        ContextOptions::create()
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use rusqlite::ToSql;
+use crate::common::BoxedValue;
+use crate::error::Error;
+
+/// The most parameters a statement can have, SQLite's default limit before 3.32.0.
+/// Every statement is checked against it, and `Insert::create_many` chunks its rows to stay under it.
+/// It is kept at 999 as the system SQLite may be older, even though newer versions allow 32766.
+pub const MAX_PARAMS: usize = 999;
+
+/// A value for a `:name` placeholder in raw sql.
+pub struct NamedValue {
+    pub(crate) name: String,
+    pub(crate) value: BoxedValue,
+}
+
+/// The parameters of a single statement.
+/// Placeholder indices are allocated in one sequence across every clause (`VALUES`, `SET`, `WHERE` etc.),
+/// so the values can be bound in order without any clause colliding with another.
+pub struct Params {
+    values: Vec<BoxedValue>,
+}
+
+impl NamedValue {
+    /// The name may be given with or without its leading `:`.
+    pub fn create<T>(name: T, value: impl ToSql + Send + 'static) -> NamedValue where T : Into<String> {
+        let name = name.into();
+
+        NamedValue {
+            name: String::from(name.trim_start_matches(':')),
+            value: Box::new(value),
+        }
+    }
+}
+
+impl Params {
+    pub fn create() -> Params {
+        Params {
+            values: Vec::new(),
+        }
+    }
+
+    /// Bind a value to the next index, returning its `?N` placeholder.
+    pub fn bind(&mut self, value: BoxedValue) -> String {
+        self.values.push(value);
+        format!("?{}", self.values.len())
+    }
+
+    /// Bind named values in raw sql, replacing each `:name` placeholder with its `?N` placeholder.
+    /// A name used more than once is bound once. Placeholders inside string literals,
+    /// quoted identifiers and comments are left alone. Other placeholder styles (`?`, `?N`, `@name`, `$name`)
+    /// would have no value or collide with the numbered ones, so they fail.
+    pub fn bind_named(&mut self, sql: &str, values: Vec<NamedValue>) -> Result<String, Error> {
+        let mut indices: HashMap<String, Option<usize>> = HashMap::new();
+        let mut named: HashMap<String, BoxedValue> = HashMap::new();
+
+        for value in values {
+            indices.insert(value.name.clone(), None);
+            named.insert(value.name, value.value);
+        }
+
+        let chars: Vec<char> = sql.chars().collect();
+        let mut result = String::with_capacity(sql.len());
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+
+            let end = match c {
+                '\'' | '"' | '`' => Params::find_closing(&chars, i, c),
+                '[' => Params::find_closing(&chars, i, ']'),
+                '-' if chars.get(i + 1) == Some(&'-') => Params::find_line_end(&chars, i),
+                '/' if chars.get(i + 1) == Some(&'*') => Params::find_comment_end(&chars, i),
+                '?' => return Err(Error::InvalidQuery("Raw sql with named values can only use `:name` placeholders")),
+                '@' | '$' if matches!(chars.get(i + 1), Some(n) if Params::is_name_char(*n)) => {
+                    return Err(Error::InvalidQuery("Raw sql with named values can only use `:name` placeholders"));
+                }
+                ':' if matches!(chars.get(i + 1), Some(n) if Params::is_name_char(*n)) => {
+                    let mut end = i + 1;
+
+                    while end < chars.len() && Params::is_name_char(chars[end]) {
+                        end += 1;
+                    }
+
+                    let name: String = chars[i + 1..end].iter().collect();
+
+                    let index = match indices.get(&name) {
+                        None => return Err(Error::UnboundParameter(name)),
+                        Some(Some(index)) => *index,
+                        Some(None) => {
+                            // Only names that are used are bound, so every index has a placeholder.
+                            let value = named.remove(&name).unwrap();
+                            self.values.push(value);
+                            indices.insert(name, Some(self.values.len()));
+                            self.values.len()
+                        }
+                    };
+
+                    result.push_str(&format!("?{}", index));
+                    i = end;
+                    continue;
+                }
+                _ => i + 1
+            };
+
+            result.extend(&chars[i..end]);
+            i = end;
+        }
+
+        match named.into_iter().next() {
+            Some((name, _)) => Err(Error::UnusedParameter(name)),
+            None => Ok(result)
+        }
+    }
+
+    /// The number of parameters bound so far, the index of the last placeholder.
+    pub fn get_count(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The values in index order, failing if there are more than SQLite allows.
+    pub fn finish(self) -> Result<Vec<BoxedValue>, Error> {
+        Params::check_count(self.values.len(), MAX_PARAMS)?;
+        Ok(self.values)
+    }
+
+    pub(crate) fn check_count(count: usize, limit: usize) -> Result<(), Error> {
+        match count > limit {
+            true => Err(Error::TooManyParameters { count, limit }),
+            false => Ok(())
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    /// The index after a quoted section starting at `start`. Doubled quotes are escapes.
+    fn find_closing(chars: &[char], start: usize, quote: char) -> usize {
+        let mut i = start + 1;
+
+        while i < chars.len() {
+            if chars[i] == quote {
+                match chars.get(i + 1) == Some(&quote) && quote != ']' {
+                    true => i += 1,
+                    false => return i + 1
+                }
+            }
+
+            i += 1;
+        }
+
+        chars.len()
+    }
+
+    fn find_line_end(chars: &[char], start: usize) -> usize {
+        match chars[start..].iter().position(|c| *c == '\n') {
+            Some(offset) => start + offset + 1,
+            None => chars.len()
+        }
+    }
+
+    fn find_comment_end(chars: &[char], start: usize) -> usize {
+        let mut i = start + 2;
+
+        while i + 1 < chars.len() {
+            if chars[i] == '*' && chars[i + 1] == '/' {
+                return i + 2;
+            }
+
+            i += 1;
+        }
+
+        chars.len()
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::create()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind(sql: &str, names: &[&str]) -> (Result<String, Error>, usize) {
+        let mut params = Params::create();
+        let values = names.iter().map(|name| NamedValue::create(*name, 1)).collect();
+        let result = params.bind_named(sql, values);
+
+        (result, params.get_count())
+    }
+
+    #[test]
+    fn binds_each_name_once() {
+        let (sql, count) = bind("SELECT * FROM t WHERE a = :a AND b = :b OR a > :a", &["a", "b"]);
+
+        assert_eq!(sql.unwrap(), "SELECT * FROM t WHERE a = ?1 AND b = ?2 OR a > ?1");
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn skips_string_literals() {
+        let (sql, count) = bind("SELECT ':a', 'it''s :a' WHERE x = :a", &["a"]);
+
+        assert_eq!(sql.unwrap(), "SELECT ':a', 'it''s :a' WHERE x = ?1");
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn skips_quoted_identifiers() {
+        let (sql, _) = bind("SELECT \":a\", `:a`, [:a] FROM t WHERE x = :a", &["a"]);
+
+        assert_eq!(sql.unwrap(), "SELECT \":a\", `:a`, [:a] FROM t WHERE x = ?1");
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        let (sql, _) = bind("SELECT 1 -- :a\nWHERE x = :a", &["a"]);
+
+        assert_eq!(sql.unwrap(), "SELECT 1 -- :a\nWHERE x = ?1");
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let (sql, _) = bind("SELECT /* :a */ 1 WHERE x = :a /* unterminated :b", &["a"]);
+
+        assert_eq!(sql.unwrap(), "SELECT /* :a */ 1 WHERE x = ?1 /* unterminated :b");
+    }
+
+    #[test]
+    fn numbers_after_existing_params() {
+        let mut params = Params::create();
+        assert_eq!(params.bind(Box::new(1)), "?1");
+        assert_eq!(params.bind(Box::new(2)), "?2");
+
+        let sql = params.bind_named("x = :a AND y = :b AND z = :a", vec![NamedValue::create(":b", 3), NamedValue::create("a", 4)]).unwrap();
+
+        assert_eq!(sql, "x = ?3 AND y = ?4 AND z = ?3");
+        assert_eq!(params.get_count(), 4);
+    }
+
+    #[test]
+    fn fails_on_mixed_styles() {
+        for sql in &["x = ?1 AND y = :a", "x = ? AND y = :a", "x = @b AND y = :a", "x = $b AND y = :a"] {
+            let (result, _) = bind(sql, &["a"]);
+            assert!(matches!(result, Err(Error::InvalidQuery(_))), "{}", sql);
+        }
+
+        let (sql, _) = bind("SELECT '?', \"@b\", 'a$b' -- ?\nWHERE x = :a", &["a"]);
+        assert_eq!(sql.unwrap(), "SELECT '?', \"@b\", 'a$b' -- ?\nWHERE x = ?1");
+    }
+
+    #[test]
+    fn fails_on_unbound_names() {
+        let (result, _) = bind("x = :a AND y = :missing", &["a"]);
+
+        assert!(matches!(result, Err(Error::UnboundParameter(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn fails_on_unused_values() {
+        let (result, _) = bind("x = :a", &["a", "b"]);
+
+        assert!(matches!(result, Err(Error::UnusedParameter(name)) if name == "b"));
+    }
+
+    #[test]
+    fn checks_the_limit() {
+        assert!(Params::check_count(MAX_PARAMS, MAX_PARAMS).is_ok());
+        assert!(matches!(Params::check_count(MAX_PARAMS + 1, MAX_PARAMS), Err(Error::TooManyParameters { .. })));
+    }
+}
@@ -0,0 +1,117 @@
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use rusqlite::Connection;
+use crate::Context;
+use crate::error::Error;
+use crate::options::ContextOptions;
+
+/// A pool of reader connections, shared by a `Context` and its `DataReader`s.
+pub(crate) struct ReaderPool {
+    connection_string: String,
+    options: ContextOptions,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    /// Connections currently open, idle or checked out.
+    open: usize,
+}
+
+/// A connection checked out of a `ReaderPool`, returned to it when dropped.
+pub(crate) struct PooledConnection {
+    connection: Option<Connection>,
+    pool: Arc<ReaderPool>,
+}
+
+impl ReaderPool {
+    pub(crate) fn create(connection_string: String, options: ContextOptions) -> ReaderPool {
+        ReaderPool {
+            connection_string,
+            options,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Check out an idle connection, opening a new one if the pool isn't full.
+    /// If it is, wait up to the checkout timeout for one to be returned.
+    pub(crate) fn get(pool: &Arc<ReaderPool>) -> Result<PooledConnection, Error> {
+        let deadline = Instant::now() + pool.options.reader_checkout_timeout;
+        let mut state = pool.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        loop {
+            if let Some(connection) = state.idle.pop() {
+                return Ok(PooledConnection { connection: Some(connection), pool: pool.clone() });
+            }
+
+            let has_room = match pool.options.reader_pool_size {
+                Some(size) => state.open < size,
+                None => true
+            };
+
+            if has_room {
+                state.open += 1;
+                // Don't hold the lock while the connection is opened and configured.
+                drop(state);
+
+                return match Context::create_connection(&pool.connection_string, &pool.options, false) {
+                    Ok(connection) => Ok(PooledConnection { connection: Some(connection), pool: pool.clone() }),
+                    Err(e) => {
+                        pool.release_slot();
+                        Err(e)
+                    }
+                };
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            if timeout == Duration::from_secs(0) {
+                return Err(Error::Timeout);
+            }
+
+            state = pool.available.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    fn put(&self, connection: Connection) {
+        // A connection left mid-transaction can't safely be reused, so it is closed instead.
+        if !connection.is_autocommit() {
+            drop(connection);
+            self.release_slot();
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.idle.push(connection);
+        self.available.notify_one();
+    }
+
+    fn release_slot(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.open -= 1;
+        self.available.notify_one();
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        // Only taken when dropped.
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put(connection);
+        }
+    }
+}
@@ -1,5 +1,12 @@
-use rusqlite::{ToSql, Connection, DatabaseName, NO_PARAMS};
-use crate::common::{BoxedValue, BlobValue, Value, Query, ValueType, BlobRef, Criteria, Queryable};
+use rusqlite::{ToSql, Connection, NO_PARAMS};
+use serde::Serialize;
+use uuid::Uuid;
+use crate::error::Error;
+use crate::identifier::Identifier;
+use crate::params::{NamedValue, Params, MAX_PARAMS};
+use crate::checksum::{ChecksumAction, ChecksumStorage};
+use crate::struct_values::{to_values, StructOptions};
+use crate::common::{BoxedValue, BlobValue, ConflictClause, OnConflict, Operator, Returning, ReturnedRow, Value, Query, ValueType, BlobRef, Criteria, Queryable, Transaction, vec_to_optional};
 
 pub struct Generic {
     sql: String,
@@ -10,6 +17,28 @@ pub struct Insert {
     sql: String,
     values: Vec<BoxedValue>,
     blobs: Option<Vec<BlobValue>>,
+    returning: Option<Returning>,
+}
+
+/// Several rows inserted by `Insert::create_many`.
+pub struct InsertMany {
+    sql: String,
+    groups: Vec<InsertGroup>,
+    /// Upsert parameters, bound before each statement's row values.
+    conflict_values: Vec<BoxedValue>,
+}
+
+enum InsertGroup {
+    /// Consecutive rows with the same fields and no blobs, inserted in multi-row chunks.
+    Rows {
+        fields: Vec<Identifier>,
+        chunk_sql: String,
+        remainder_sql: Option<String>,
+        rows_per_chunk: usize,
+        values: Vec<Vec<BoxedValue>>,
+    },
+    /// A row with blob values, inserted on its own so its blobs can be written to its rowid.
+    Blob(Insert),
 }
 
 pub struct Create {
@@ -20,23 +49,94 @@ pub struct Update {
     sql: String,
     values: Vec<BoxedValue>,
     blobs: Option<Vec<BlobValue>>,
+    /// Selects the rowids the update will match, so blobs can be written to them.
+    select_sql: String,
+    /// The number of `SET` parameters, the criteria parameters follow them in `values`.
+    criteria_offset: usize,
+    allow_multiple: bool,
+    returning: Option<Returning>,
 }
 
 pub struct Delete {
     sql: String,
     values: Option<Vec<BoxedValue>>,
+    returning: Option<Returning>,
 }
 
 pub struct UpdateBlob {
     sql: String,
-    data: Vec<u8>,
+    blob: BlobValue,
     row_id: i64,
-    table_name: String,
-    field_name: String,
+}
+
+/// A nested sub-transaction, run inside a `SAVEPOINT`.
+/// If any of its queries fail only its own changes are rolled back
+/// and the enclosing transaction carries on.
+pub struct Savepoint {
+    sql: String,
+    name: String,
+    queries: Transaction,
+}
+
+/// A `SELECT` built up from its clauses, run with `DataReader::select`
+/// or used as a subquery in a source, a column or criteria.
+pub struct Select {
+    distinct: bool,
+    columns: Vec<SelectColumn>,
+    source: SelectSource,
+    joins: Vec<Join>,
+    criteria: Option<Criteria>,
+    group_by: Vec<Identifier>,
+    having: Option<Criteria>,
+    order_by: Vec<(Identifier, Order)>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+/// A table or subquery to select from or join.
+pub enum SelectSource {
+    Table { table: Identifier, alias: Option<Identifier> },
+    Subquery { select: Box<Select>, alias: Identifier },
+}
+
+pub enum SelectColumn {
+    Field { field: Identifier, alias: Option<Identifier> },
+    /// Every column of a table, `"table".*`.
+    AllOf(Identifier),
+    /// An SQL expression, i.e. `count(*)`.
+    Expression { sql: String, alias: Option<Identifier> },
+    /// A scalar subquery.
+    Subquery { select: Box<Select>, alias: Identifier },
+}
+
+pub enum JoinType {
+    Inner,
+    Left,
+}
+
+/// The `ON` clause of a join, every part must hold.
+pub struct JoinCondition {
+    parts: Vec<JoinPart>,
+}
+
+enum JoinPart {
+    Columns { left: Identifier, operator: Operator, right: Identifier },
+    Criteria(Criteria),
+}
+
+struct Join {
+    join_type: JoinType,
+    source: SelectSource,
+    condition: JoinCondition,
+}
+
+pub enum Order {
+    Ascending,
+    Descending,
 }
 
 impl Generic {
-    pub fn create<T>(sql: T, values: Vec<impl ToSql + Send + 'static>) -> Result<Query, &'static str> where T : Into<String> {
+    pub fn create<T>(sql: T, values: Vec<impl ToSql + Send + 'static>) -> Result<Query, Error> where T : Into<String> {
 
         //let params = params! [ values ];
 
@@ -51,57 +151,220 @@ impl Generic {
             values: params,
         }))
     }
+
+    /// Create a query from sql with `:name` placeholders, i.e. `UPDATE t SET n = :n WHERE id = :id`.
+    pub fn create_named<T>(sql: T, values: Vec<NamedValue>) -> Result<Query, Error> where T : Into<String> {
+        let mut params = Params::create();
+        let sql = params.bind_named(&sql.into(), values)?;
+
+        Ok(Box::new(Generic {
+            sql,
+            values: params.finish()?,
+        }))
+    }
 }
 
 impl Insert {
-    pub fn create<T>(table_name: T, values: Vec<Value>) -> Result<Query, &'static str> where T : Into<String> {
-        
-        let table_name = table_name.into();
-        
-        let (fields, params_string, values, blobs) = Insert::handle_values(&table_name, values)?;
-        let sql = format!("INSERT INTO {} ({}) VALUES ({});", table_name, fields, params_string);
+    pub fn create<T>(table_name: T, values: Vec<Value>) -> Result<Query, Error> where T : Into<Identifier> {
+        Ok(Box::new(Insert::handle_create(&table_name.into(), values, None, None, Params::create())?))
+    }
+
+    /// Create an insert that returns the mapped `returning` rows, i.e. generated ids and defaults,
+    /// in `WriteOutcome::returned`. Blob columns are returned before the blob data is written.
+    pub fn create_returning<T>(table_name: T, values: Vec<Value>, returning: Returning) -> Result<Query, Error> where T : Into<Identifier> {
+        Ok(Box::new(Insert::handle_create(&table_name.into(), values, None, Some(returning), Params::create())?))
+    }
+
+    /// Create an insert that resolves conflicts with `on_conflict`.
+    /// Blob values can't be combined with `OnConflict::DoUpdate`, and aren't written if the row is ignored.
+    pub fn create_with_conflict<T>(table_name: T, values: Vec<Value>, on_conflict: OnConflict) -> Result<Query, Error> where T : Into<Identifier> {
+        let mut params = Params::create();
+        let clause = on_conflict.bind(&mut params)?;
+
+        Ok(Box::new(Insert::handle_create(&table_name.into(), values, Some(&clause), None, params)?))
+    }
+
+    /// Create an insert from the fields of a struct, see `struct_values::to_values`.
+    pub fn from_struct<T, S>(table_name: T, value: &S) -> Result<Query, Error> where T : Into<Identifier>, S : Serialize {
+        Insert::create(table_name, to_values(value, &StructOptions::create())?)
+    }
+
+    /// Like `from_struct`, with fields skipped, renamed or written as blobs by `options`.
+    pub fn from_struct_with_options<T, S>(table_name: T, value: &S, options: StructOptions) -> Result<Query, Error> where T : Into<Identifier>, S : Serialize {
+        Insert::create(table_name, to_values(value, &options)?)
+    }
+
+    /// Insert several rows in one query. Consecutive rows with the same fields are inserted
+    /// with multi-row `VALUES` statements, chunked to stay under SQLite's host parameter limit.
+    /// Rows with blob values are inserted one at a time. The query fails as a whole if any row fails.
+    pub fn create_many<T>(table_name: T, rows: Vec<Vec<Value>>) -> Result<Query, Error> where T : Into<Identifier> {
+        Insert::handle_create_many(table_name.into(), rows, None)
+    }
+
+    /// Like `create_many`, resolving conflicts on every row with `on_conflict`.
+    pub fn create_many_with_conflict<T>(table_name: T, rows: Vec<Vec<Value>>, on_conflict: OnConflict) -> Result<Query, Error> where T : Into<Identifier> {
+        Insert::handle_create_many(table_name.into(), rows, Some(on_conflict))
+    }
+
+    fn handle_create_many(table_name: Identifier, rows: Vec<Vec<Value>>, on_conflict: Option<OnConflict>) -> Result<Query, Error> {
+        let mut groups: Vec<InsertGroup> = Vec::new();
+
+        // The conflict clause is bound first, so its placeholders are the same in every statement
+        // and the row values follow them.
+        let mut conflict_params = Params::create();
+        let clause = match on_conflict {
+            Some(on_conflict) => Some(on_conflict.bind(&mut conflict_params)?),
+            None => None
+        };
+        let offset = conflict_params.get_count();
+
+        for row in rows {
+            if row.is_empty() {
+                return Err(Error::InvalidQuery("Inserted rows must have at least one value"));
+            }
+
+            Params::check_count(row.len() + offset, MAX_PARAMS)?;
+
+            let has_blob = row.iter().any(|value| match value.value {
+                ValueType::Blob(_) => true,
+                ValueType::BoxedValue(_) => false
+            });
+
+            // Blob rows can't use `DoUpdate`, so the clause has no parameters of its own here.
+            if has_blob {
+                groups.push(InsertGroup::Blob(Insert::handle_create(&table_name, row, clause.as_ref(), None, Params::create())?));
+                continue;
+            }
+
+            let fields: Vec<Identifier> = row.iter().map(|value| value.field.clone()).collect();
+            let values: Vec<BoxedValue> = row.into_iter().filter_map(|value| match value.value {
+                ValueType::BoxedValue(boxed) => Some(boxed),
+                ValueType::Blob(_) => None
+            }).collect();
+
+            match groups.last_mut() {
+                Some(InsertGroup::Rows { fields: group_fields, values: group_values, .. }) if *group_fields == fields => {
+                    group_values.push(values);
+                }
+                _ => {
+                    groups.push(InsertGroup::Rows {
+                        chunk_sql: String::new(),
+                        remainder_sql: None,
+                        rows_per_chunk: (MAX_PARAMS - offset) / fields.len(),
+                        fields,
+                        values: vec![values],
+                    });
+                }
+            }
+        }
+
+        // The statements are built once each group's row count is known.
+        for group in groups.iter_mut() {
+            if let InsertGroup::Rows { fields, chunk_sql, remainder_sql, rows_per_chunk, values } = group {
+                let remainder = values.len() % *rows_per_chunk;
+
+                *chunk_sql = Insert::get_multi_row_sql(&table_name, fields, values.len().min(*rows_per_chunk), offset, clause.as_ref())?;
+                *remainder_sql = match (values.len() > *rows_per_chunk, remainder) {
+                    (true, r) if r > 0 => Some(Insert::get_multi_row_sql(&table_name, fields, r, offset, clause.as_ref())?),
+                    _ => None
+                };
+            }
+        }
 
+        let sql = match groups.first() {
+            Some(InsertGroup::Rows { chunk_sql, .. }) => chunk_sql.clone(),
+            Some(InsertGroup::Blob(insert)) => insert.sql.clone(),
+            None => String::new()
+        };
 
-        Ok(Box::new(Insert {
+        Ok(Box::new(InsertMany {
             sql,
-            values,
-            blobs,
+            groups,
+            conflict_values: conflict_params.finish()?,
         }))
     }
 
-    fn handle_values(table_name: &str, values: Vec<Value>) -> Result<(String, String, Vec<BoxedValue>, Option<Vec<BlobValue>>), &'static str> {
+    /// Build a single row insert, binding its values after any already in `params`.
+    fn handle_create(table_name: &Identifier, values: Vec<Value>, clause: Option<&ConflictClause>, returning: Option<Returning>, mut params: Params) -> Result<Insert, Error> {
+        let (fields, params_string, blobs) = Insert::handle_values(table_name, values, &mut params)?;
+
+        // The rowid of an updated row isn't known, so there would be nowhere to write the blobs.
+        if let (Some(ConflictClause { updates: true, .. }), Some(_)) = (clause, &blobs) {
+            return Err(Error::InvalidQuery("Blob values can't be used with OnConflict::DoUpdate"));
+        }
+
+        let returning_sql = match &returning {
+            Some(returning) => returning.get_sql()?,
+            None => String::new()
+        };
+
+        let sql = match clause {
+            None => format!("INSERT INTO {} ({}) VALUES ({}){};", table_name.get_sql()?, fields, params_string, returning_sql),
+            Some(clause) => format!("{} {} ({}) VALUES ({}){}{};", clause.prefix, table_name.get_sql()?, fields, params_string, clause.sql, returning_sql)
+        };
+
+        Ok(Insert {
+            sql,
+            values: params.finish()?,
+            blobs,
+            returning,
+        })
+    }
+
+    /// `INSERT INTO table (fields) VALUES (?1, ?2), (?3, ?4), ...` for `row_count` rows,
+    /// with placeholders numbered from `offset + 1`.
+    fn get_multi_row_sql(table_name: &Identifier, fields: &[Identifier], row_count: usize, offset: usize, clause: Option<&ConflictClause>) -> Result<String, Error> {
+        let field_names = fields.iter().map(|field| field.get_name_sql()).collect::<Result<Vec<String>, Error>>()?;
+
+        let rows: Vec<String> = (0..row_count).map(|row| {
+            let params: Vec<String> = (1..=fields.len()).map(|n| format!("?{}", offset + row * fields.len() + n)).collect();
+            format!("({})", params.join(", "))
+        }).collect();
+
+        Ok(match clause {
+            None => format!("INSERT INTO {} ({}) VALUES {};", table_name.get_sql()?, field_names.join(", "), rows.join(", ")),
+            Some(clause) => format!("{} {} ({}) VALUES {}{};", clause.prefix, table_name.get_sql()?, field_names.join(", "), rows.join(", "), clause.sql)
+        })
+    }
+
+    fn handle_values(table_name: &Identifier, values: Vec<Value>, params: &mut Params) -> Result<(String, String, Option<Vec<BlobValue>>), Error> {
         let mut fields = Vec::new();
         let mut params_string = Vec::new();
-        let mut result_values: Vec<BoxedValue> = Vec::new();
         let mut blobs: Vec<BlobValue> = Vec::new();
 
-        let mut counter: u8 = 1;
-
         for value in values {
-            println!("TEST");
-
-            fields.push(value.field.clone());
+            fields.push(value.field.get_name_sql()?);
 
             match value.value {
                 ValueType::BoxedValue(boxed) => {
-                    params_string.push(format!("?{}", counter));
-                    result_values.push(boxed);
-                    counter = counter + 1;
+                    params_string.push(params.bind(boxed));
                 }
                 ValueType::Blob(blob) => {
-                    let loaded_blob = BlobRef::get(table_name.clone(), value.field.as_str(), blob)?;
-                    params_string.push(format!("ZEROBLOB({})", loaded_blob.data.len()));
+                    let mut loaded_blob = BlobRef::get(table_name, &value.field, blob)?;
+                    loaded_blob.checksum = value.checksum;
+                    params_string.push(format!("ZEROBLOB({})", loaded_blob.get_size()));
                     blobs.push(loaded_blob);
                 }
             }
         };
 
-        Ok((fields.join(", "), params_string.join(", "), result_values, vec_to_optional(blobs)))
+        Ok((fields.join(", "), params_string.join(", "), vec_to_optional(blobs)))
+    }
+
+    fn execute_with_blobs(&self, connection: &Connection, blobs: &Vec<BlobValue>) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        let (rows, returned) = execute_returning_sql(connection, &self.sql, &self.values, &self.returning)?;
+
+        // Nothing was inserted if the row was ignored, so there's nowhere to write the blobs.
+        if rows > 0 {
+            write_blobs(connection, blobs, connection.last_insert_rowid())?;
+        }
+
+        Ok((rows, returned))
     }
 }
 
 impl Create {
-    pub fn create<T>(sql: T) -> Result<Query, &'static str> where T : Into<String> {
+    pub fn create<T>(sql: T) -> Result<Query, Error> where T : Into<String> {
         Ok(Box::new(Create {
             sql: sql.into()
         }))
@@ -109,104 +372,467 @@ impl Create {
 }
 
 impl Update {
-    pub fn create<T>(table_name: T, values: Vec<Value>, criteria: Criteria) -> Result<Query, &'static str> where T : Into<String> {
+    /// Create an update. If any of the values are blobs the criteria must match exactly one row,
+    /// otherwise the query will fail with `Error::UnexpectedRowCount` when executed.
+    pub fn create<T>(table_name: T, values: Vec<Value>, criteria: Criteria) -> Result<Query, Error> where T : Into<Identifier> {
+        Update::handle_create(table_name.into(), values, criteria, false, None)
+    }
 
-        let table_name = table_name.into();
-        
-        let (params_string, mut values, blobs) = Update::handle_values(&table_name, values)?;
-        let (criteria_string, params) = Criteria::handle(criteria);
+    /// Like `create`, returning the mapped `returning` rows in `WriteOutcome::returned`.
+    /// Blob columns are returned before the blob data is written.
+    pub fn create_returning<T>(table_name: T, values: Vec<Value>, criteria: Criteria, returning: Returning) -> Result<Query, Error> where T : Into<Identifier> {
+        Update::handle_create(table_name.into(), values, criteria, false, Some(returning))
+    }
 
-        let sql = format!("UPDATE {} SET {} WHERE {};", table_name, params_string, criteria_string);
+    /// Create an update setting every field of a struct, see `struct_values::to_values`.
+    pub fn from_struct<T, S>(table_name: T, value: &S, criteria: Criteria) -> Result<Query, Error> where T : Into<Identifier>, S : Serialize {
+        Update::create(table_name, to_values(value, &StructOptions::create())?, criteria)
+    }
 
-        match params {
-            None => {}
-            Some(mut p) => values.append(&mut p)
-        }
+    /// Like `from_struct`, with fields skipped, renamed or written as blobs by `options`,
+    /// i.e. skipping the key the criteria matches on.
+    pub fn from_struct_with_options<T, S>(table_name: T, value: &S, criteria: Criteria, options: StructOptions) -> Result<Query, Error> where T : Into<Identifier>, S : Serialize {
+        Update::create(table_name, to_values(value, &options)?, criteria)
+    }
+
+    /// Like `create`, but blob values may be written to every row the criteria matches.
+    pub fn create_multiple<T>(table_name: T, values: Vec<Value>, criteria: Criteria) -> Result<Query, Error> where T : Into<Identifier> {
+        Update::handle_create(table_name.into(), values, criteria, true, None)
+    }
+
+    fn handle_create(table_name: Identifier, values: Vec<Value>, criteria: Criteria, allow_multiple: bool, returning: Option<Returning>) -> Result<Query, Error> {
+        let mut params = Params::create();
+        let (params_string, blobs) = Update::handle_values(&table_name, values, &mut params)?;
+        let criteria_offset = params.get_count();
+        let criteria_string = Criteria::bind(criteria, &mut params)?;
+
+        let returning_sql = match &returning {
+            Some(returning) => returning.get_sql()?,
+            None => String::new()
+        };
+        let sql = format!("UPDATE {} SET {} WHERE {}{};", table_name.get_sql()?, params_string, criteria_string, returning_sql);
+        let select_sql = format!("SELECT rowid FROM {} WHERE {};", table_name.get_sql()?, criteria_string);
 
         Ok(Box::new(Update {
             sql,
-            values,
+            values: params.finish()?,
             blobs,
+            select_sql,
+            criteria_offset,
+            allow_multiple,
+            returning,
         }))
     }
 
+    /// Find the rowids matching the criteria, binding only the criteria parameters.
+    fn select_row_ids(&self, connection: &Connection) -> Result<Vec<i64>, Error> {
+        let mut statement = connection.prepare(&self.select_sql).map_err(|e| Error::from_prepare(&self.select_sql, e))?;
 
-    fn handle_values(table_name: &str, values: Vec<Value>) -> Result<(String, Vec<BoxedValue>, Option<Vec<BlobValue>>), &'static str> {
-        //let mut fields = Vec::new();
-        let mut params_string = Vec::new();
-        let mut result_values: Vec<BoxedValue> = Vec::new();
-        let mut blobs: Vec<BlobValue> = Vec::new();
-        let mut counter: u8 = 1;
+        for (index, value) in self.values.iter().enumerate().skip(self.criteria_offset) {
+            statement.raw_bind_parameter(index + 1, value).map_err(|e| Error::from_execute(&self.select_sql, e))?;
+        }
 
+        let mut rows = statement.raw_query();
+        let mut row_ids = Vec::new();
 
-        for value in values {
+        while let Some(row) = rows.next().map_err(|e| Error::from_execute(&self.select_sql, e))? {
+            row_ids.push(row.get(0).map_err(|e| Error::from_execute(&self.select_sql, e))?);
+        }
+
+        Ok(row_ids)
+    }
+
+    fn execute_with_blobs(&self, connection: &Connection, blobs: &Vec<BlobValue>) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        let row_ids = self.select_row_ids(connection)?;
+
+        if row_ids.is_empty() || (row_ids.len() > 1 && !self.allow_multiple) {
+            return Err(Error::UnexpectedRowCount { sql: self.select_sql.clone(), matched: row_ids.len() });
+        }
 
-            //fields.push(value.field.clone());
+        let result = execute_returning_sql(connection, &self.sql, &self.values, &self.returning)?;
 
+        for row_id in row_ids {
+            write_blobs(connection, blobs, row_id)?;
+        }
+
+        Ok(result)
+    }
+
+
+    fn handle_values(table_name: &Identifier, values: Vec<Value>, params: &mut Params) -> Result<(String, Option<Vec<BlobValue>>), Error> {
+        let mut params_string = Vec::new();
+        let mut blobs: Vec<BlobValue> = Vec::new();
+
+        for value in values {
             match value.value {
                 ValueType::BoxedValue(boxed) => {
-                    //fields.push(value.field);
-                    params_string.push(format!("{} = ?{}", value.field, counter));
-                    result_values.push(boxed);
-                    counter = counter + 1;
+                    params_string.push(format!("{} = {}", value.field.get_name_sql()?, params.bind(boxed)));
                 }
                 ValueType::Blob(blob) => {
-                    let loaded_blob = BlobRef::get(table_name.clone(), value.field.as_str(), blob)?;
-                    params_string.push(format!("{} = ZEROBLOB({})", value.field, loaded_blob.data.len()));
+                    let mut loaded_blob = BlobRef::get(table_name, &value.field, blob)?;
+                    loaded_blob.checksum = value.checksum;
+                    params_string.push(format!("{} = ZEROBLOB({})", value.field.get_name_sql()?, loaded_blob.get_size()));
                     blobs.push(loaded_blob);
                 }
             }
         };
 
-        Ok((params_string.join(", "), result_values, vec_to_optional(blobs)))
+        Ok((params_string.join(", "), vec_to_optional(blobs)))
     }
 }
 
 impl Delete {
-    pub fn create<T>(table_name: T, criteria: Criteria) -> Result<Query, &'static str> where T : Into<String> {
+    pub fn create<T>(table_name: T, criteria: Criteria) -> Result<Query, Error> where T : Into<Identifier> {
+        Delete::handle_create(table_name.into(), criteria, None)
+    }
+
+    /// Like `create`, returning the mapped `returning` rows in `WriteOutcome::returned`.
+    pub fn create_returning<T>(table_name: T, criteria: Criteria, returning: Returning) -> Result<Query, Error> where T : Into<Identifier> {
+        Delete::handle_create(table_name.into(), criteria, Some(returning))
+    }
 
-        let table_name = table_name.into();
-        
-        let (criteria_string, params) = Criteria::handle(criteria);
+    fn handle_create(table_name: Identifier, criteria: Criteria, returning: Option<Returning>) -> Result<Query, Error> {
+        let (criteria_string, params) = Criteria::handle(criteria)?;
 
-        let sql = format!("DELETE FROM {} WHERE {};", table_name, criteria_string);
+        let returning_sql = match &returning {
+            Some(returning) => returning.get_sql()?,
+            None => String::new()
+        };
+        let sql = format!("DELETE FROM {} WHERE {}{};", table_name.get_sql()?, criteria_string, returning_sql);
 
 
         Ok(Box::new(Delete {
             sql,
             values: params,
+            returning,
         }))
     }
 }
 
 impl UpdateBlob {
-    pub fn create<T>(table_name: T, field_name: T, row_id: i64, data: Vec<u8>) -> Result<Query, &'static str> where T : Into<String>{
+    pub fn create<T>(table_name: T, field_name: T, row_id: i64, data: Vec<u8>) -> Result<Query, Error> where T : Into<Identifier>{
+        UpdateBlob::handle_create(table_name.into(), field_name.into(), row_id, data, None)
+    }
 
-        let table_name = table_name.into();
-        let field_name = field_name.into();
-        
-        let sql = format!("UPDATE {} SET {} = ZEROBLOB({}) WHERE rowid = {};", table_name.clone(), field_name.clone(), data.len(), row_id);
+    /// Like `create`, but also stores the SHA-256 of the data so it can be verified when read.
+    pub fn create_with_checksum<T>(table_name: T, field_name: T, row_id: i64, data: Vec<u8>, storage: ChecksumStorage) -> Result<Query, Error> where T : Into<Identifier>{
+        UpdateBlob::handle_create(table_name.into(), field_name.into(), row_id, data, Some(ChecksumAction::Store(storage)))
+    }
+
+    /// Like `create`, for a field with checksums in `storage`, removing the checksum of the earlier data.
+    pub fn create_clearing_checksum<T>(table_name: T, field_name: T, row_id: i64, data: Vec<u8>, storage: ChecksumStorage) -> Result<Query, Error> where T : Into<Identifier>{
+        UpdateBlob::handle_create(table_name.into(), field_name.into(), row_id, data, Some(ChecksumAction::Clear(storage)))
+    }
+
+    fn handle_create(table_name: Identifier, field_name: Identifier, row_id: i64, data: Vec<u8>, checksum: Option<ChecksumAction>) -> Result<Query, Error> {
+        let mut blob = BlobRef::get(table_name, field_name, BlobRef::Memory(data))?;
+        blob.checksum = checksum;
+
+        let sql = format!("UPDATE {} SET {} = ZEROBLOB({}) WHERE rowid = {};", blob.table.get_sql()?, blob.field.get_name_sql()?, blob.get_size(), row_id);
 
         Ok(Box::new(UpdateBlob {
             sql,
-            data,
+            blob,
             row_id,
-            table_name,
-            field_name
         }))
     }
 }
 
-impl Queryable for Generic {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
-        match connection.execute(&self.sql, &self.values) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                println!("Err: {:?}", err);
-                Err("Could not execute `INSERT`. Table might not exist, there is an issue with the query or the database is unavailable.")
+impl Savepoint {
+    pub fn create(queries: Transaction) -> Result<Query, Error> {
+        let name = format!("rusq_{}", Uuid::new_v4().to_simple());
+        let sql = format!("SAVEPOINT {};", name);
+
+        Ok(Box::new(Savepoint {
+            sql,
+            name,
+            queries,
+        }))
+    }
+}
+
+impl Select {
+    /// Select every column from `source`, until columns are added.
+    pub fn create<T>(source: T) -> Select where T : Into<SelectSource> {
+        Select {
+            distinct: false,
+            columns: Vec::new(),
+            source: source.into(),
+            joins: Vec::new(),
+            criteria: None,
+            group_by: Vec::new(),
+            having: None,
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        }
+    }
+
+    /// Add fields. Use `Identifier::qualified` for a field of a specific table, i.e. `("orders", "id")`.
+    pub fn columns<T>(mut self, fields: Vec<T>) -> Select where T : Into<Identifier> {
+        for field in fields {
+            self.columns.push(SelectColumn::Field { field: field.into(), alias: None });
+        }
+
+        self
+    }
+
+    pub fn column_as<T, U>(mut self, field: T, alias: U) -> Select where T : Into<Identifier>, U : Into<Identifier> {
+        self.columns.push(SelectColumn::Field { field: field.into(), alias: Some(alias.into()) });
+        self
+    }
+
+    pub fn column(mut self, column: SelectColumn) -> Select {
+        self.columns.push(column);
+        self
+    }
+
+    pub fn distinct(mut self) -> Select {
+        self.distinct = true;
+        self
+    }
+
+    pub fn inner_join<T>(self, source: T, condition: JoinCondition) -> Select where T : Into<SelectSource> {
+        self.join(JoinType::Inner, source, condition)
+    }
+
+    pub fn left_join<T>(self, source: T, condition: JoinCondition) -> Select where T : Into<SelectSource> {
+        self.join(JoinType::Left, source, condition)
+    }
+
+    pub fn join<T>(mut self, join_type: JoinType, source: T, condition: JoinCondition) -> Select where T : Into<SelectSource> {
+        self.joins.push(Join { join_type, source: source.into(), condition });
+        self
+    }
+
+    /// The `WHERE` clause.
+    pub fn criteria(mut self, criteria: Criteria) -> Select {
+        self.criteria = Some(criteria);
+        self
+    }
+
+    pub fn group_by<T>(mut self, fields: Vec<T>) -> Select where T : Into<Identifier> {
+        self.group_by.extend(fields.into_iter().map(|field| field.into()));
+        self
+    }
+
+    /// The `HAVING` clause. Aggregates can be compared by their column alias, i.e. `Criteria::field("total").gt(10)`.
+    pub fn having(mut self, criteria: Criteria) -> Select {
+        self.having = Some(criteria);
+        self
+    }
+
+    /// Order by a field or column alias, after any ordering already added.
+    pub fn order_by<T>(mut self, field: T, order: Order) -> Select where T : Into<Identifier> {
+        self.order_by.push((field.into(), order));
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Select {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Select {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Compile the select into sql and its parameters.
+    pub fn build(self) -> Result<(String, Vec<BoxedValue>), Error> {
+        let mut params = Params::create();
+        let sql = self.bind(&mut params)?;
+
+        Ok((sql, params.finish()?))
+    }
+
+    /// Compile the select, binding its values to `params` after any already bound, so it can be nested.
+    pub(crate) fn bind(self, params: &mut Params) -> Result<String, Error> {
+        let mut sql = match self.distinct {
+            true => String::from("SELECT DISTINCT "),
+            false => String::from("SELECT ")
+        };
+
+        match self.columns.is_empty() {
+            true => sql.push('*'),
+            false => {
+                let mut columns = Vec::new();
+
+                for column in self.columns {
+                    columns.push(column.bind(params)?);
+                }
+
+                sql.push_str(&columns.join(", "));
+            }
+        }
+
+        sql.push_str(&format!(" FROM {}", self.source.bind(params)?));
+
+        for join in self.joins {
+            let source = join.source.bind(params)?;
+            let condition = join.condition.bind(params)?;
+
+            sql.push_str(&format!(" {} {} ON {}", join.join_type.get_sql(), source, condition));
+        }
+
+        if let Some(criteria) = self.criteria {
+            sql.push_str(&format!(" WHERE {}", Criteria::bind(criteria, params)?));
+        }
+
+        if !self.group_by.is_empty() {
+            let fields = self.group_by.iter().map(|field| field.get_sql()).collect::<Result<Vec<String>, Error>>()?;
+            sql.push_str(&format!(" GROUP BY {}", fields.join(", ")));
+        }
+
+        if let Some(criteria) = self.having {
+            sql.push_str(&format!(" HAVING {}", Criteria::bind(criteria, params)?));
+        }
+
+        if !self.order_by.is_empty() {
+            let mut order = Vec::new();
+
+            for (field, direction) in self.order_by.iter() {
+                order.push(format!("{} {}", field.get_sql()?, direction.get_sql()));
             }
+
+            sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        }
+
+        // SQLite only allows `OFFSET` after a `LIMIT`, `-1` is no limit.
+        match (self.limit, self.offset) {
+            (Some(limit), Some(offset)) => sql.push_str(&format!(" LIMIT {} OFFSET {}", limit, offset)),
+            (Some(limit), None) => sql.push_str(&format!(" LIMIT {}", limit)),
+            (None, Some(offset)) => sql.push_str(&format!(" LIMIT -1 OFFSET {}", offset)),
+            (None, None) => {}
+        }
+
+        Ok(sql)
+    }
+}
+
+impl SelectSource {
+    pub fn table_as<T, U>(table: T, alias: U) -> SelectSource where T : Into<Identifier>, U : Into<Identifier> {
+        SelectSource::Table { table: table.into(), alias: Some(alias.into()) }
+    }
+
+    pub fn subquery<T>(select: Select, alias: T) -> SelectSource where T : Into<Identifier> {
+        SelectSource::Subquery { select: Box::new(select), alias: alias.into() }
+    }
+
+    fn bind(self, params: &mut Params) -> Result<String, Error> {
+        match self {
+            SelectSource::Table { table, alias: None } => table.get_sql(),
+            SelectSource::Table { table, alias: Some(alias) } => Ok(format!("{} AS {}", table.get_sql()?, alias.get_name_sql()?)),
+            SelectSource::Subquery { select, alias } => Ok(format!("({}) AS {}", select.bind(params)?, alias.get_name_sql()?))
+        }
+    }
+}
+
+impl<T> From<T> for SelectSource where T : Into<Identifier> {
+    fn from(table: T) -> Self {
+        SelectSource::Table { table: table.into(), alias: None }
+    }
+}
+
+impl SelectColumn {
+    pub fn all_of<T>(table: T) -> SelectColumn where T : Into<Identifier> {
+        SelectColumn::AllOf(table.into())
+    }
+
+    pub fn expression<T, U>(sql: T, alias: U) -> SelectColumn where T : Into<String>, U : Into<Identifier> {
+        SelectColumn::Expression { sql: sql.into(), alias: Some(alias.into()) }
+    }
+
+    pub fn subquery<T>(select: Select, alias: T) -> SelectColumn where T : Into<Identifier> {
+        SelectColumn::Subquery { select: Box::new(select), alias: alias.into() }
+    }
+
+    fn bind(self, params: &mut Params) -> Result<String, Error> {
+        let (sql, alias) = match self {
+            SelectColumn::Field { field, alias } => (field.get_sql()?, alias),
+            SelectColumn::AllOf(table) => (format!("{}.*", table.get_sql()?), None),
+            SelectColumn::Expression { sql, alias } => (sql, alias),
+            SelectColumn::Subquery { select, alias } => (format!("({})", select.bind(params)?), Some(alias))
+        };
+
+        match alias {
+            Some(alias) => Ok(format!("{} AS {}", sql, alias.get_name_sql()?)),
+            None => Ok(sql)
         }
     }
+}
+
+impl JoinType {
+    fn get_sql(&self) -> &'static str {
+        match self {
+            JoinType::Inner => "INNER JOIN",
+            JoinType::Left => "LEFT JOIN"
+        }
+    }
+}
+
+impl JoinCondition {
+    /// `left = right`, i.e. `JoinCondition::eq(Identifier::qualified("orders", "user_id")?, Identifier::qualified("users", "id")?)`.
+    pub fn eq<T, U>(left: T, right: U) -> JoinCondition where T : Into<Identifier>, U : Into<Identifier> {
+        JoinCondition::compare(left, Operator::Equal, right)
+    }
+
+    /// Compare two columns with a comparison operator (`=`, `<>`, `<`, `<=`, `>`, `>=`, `LIKE` or `GLOB`).
+    pub fn compare<T, U>(left: T, operator: Operator, right: U) -> JoinCondition where T : Into<Identifier>, U : Into<Identifier> {
+        JoinCondition {
+            parts: vec![JoinPart::Columns { left: left.into(), operator, right: right.into() }]
+        }
+    }
+
+    /// Criteria on values rather than columns, i.e. to only join active rows.
+    pub fn criteria(criteria: Criteria) -> JoinCondition {
+        JoinCondition {
+            parts: vec![JoinPart::Criteria(criteria)]
+        }
+    }
+
+    pub fn and(mut self, other: JoinCondition) -> JoinCondition {
+        self.parts.extend(other.parts);
+        self
+    }
+
+    fn bind(self, params: &mut Params) -> Result<String, Error> {
+        let mut parts = Vec::new();
+
+        for part in self.parts {
+            let sql = match part {
+                JoinPart::Columns { operator: Operator::In, .. }
+                | JoinPart::Columns { operator: Operator::NotIn, .. }
+                | JoinPart::Columns { operator: Operator::Between, .. }
+                | JoinPart::Columns { operator: Operator::IsNull, .. }
+                | JoinPart::Columns { operator: Operator::IsNotNull, .. } => return Err(Error::InvalidQuery("Join columns can only be compared with comparison operators.")),
+                JoinPart::Columns { left, operator, right } => format!("{} {} {}", left.get_sql()?, operator.get_sql(), right.get_sql()?),
+                JoinPart::Criteria(criteria) => format!("({})", Criteria::bind(criteria, params)?)
+            };
+
+            parts.push(sql);
+        }
+
+        match parts.is_empty() {
+            true => Err(Error::InvalidQuery("A join requires a condition.")),
+            false => Ok(parts.join(" AND "))
+        }
+    }
+}
+
+impl Order {
+    fn get_sql(&self) -> &'static str {
+        match self {
+            Order::Ascending => "ASC",
+            Order::Descending => "DESC"
+        }
+    }
+}
+
+impl Queryable for Generic {
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        execute_sql(connection, &self.sql, &self.values)
+    }
 
     fn get_type_name(&self) -> &'static str {
         "GENERIC"
@@ -218,32 +844,63 @@ impl Queryable for Generic {
 }
 
 impl Queryable for Insert {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
-        match connection.execute(&self.sql, &self.values) {
-            Ok(_) => {
-                match &self.blobs {
-                    None => Ok(()),
-                    Some(blobs) => {
-                        let row_id = connection.last_insert_rowid();
-
-                        for blob in blobs {
-                            let mut b = connection.blob_open(DatabaseName::Main, blob.table.as_str(), blob.field.as_str(), row_id, false).unwrap();
-                            b.write_at(blob.data.as_slice(), 0);
-                        }
-
-                        Ok(())
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        self.execute_returning(connection).map(|(rows, _)| rows)
+    }
+
+    fn execute_returning(&self, connection: &Connection) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        match &self.blobs {
+            None => execute_returning_sql(connection, &self.sql, &self.values, &self.returning),
+            // Run in a savepoint so a failed blob write doesn't leave a row with zeroed blobs.
+            Some(blobs) => with_savepoint(connection, "rusq_insert", || self.execute_with_blobs(connection, blobs))
+        }
+    }
+
+    fn get_type_name(&self) -> &'static str {
+        "INSERT"
+    }
+
+    fn get_raw_sql(&self) -> &'_ str {
+        self.sql.as_str()
+    }
+}
+
+impl InsertMany {
+    fn handle_execute(&self, connection: &Connection) -> Result<usize, Error> {
+        let mut rows = 0;
+
+        for group in &self.groups {
+            match group {
+                InsertGroup::Blob(insert) => rows += insert.execute(connection)?,
+                InsertGroup::Rows { chunk_sql, remainder_sql, rows_per_chunk, values, .. } => {
+                    for chunk in values.chunks(*rows_per_chunk) {
+                        let sql = match (chunk.len() < *rows_per_chunk, remainder_sql) {
+                            (true, Some(remainder_sql)) => remainder_sql,
+                            _ => chunk_sql
+                        };
+
+                        // Full chunks share one cached statement, so it is only prepared once.
+                        let mut statement = connection.prepare_cached(sql).map_err(|e| Error::from_prepare(sql, e))?;
+
+                        let params = self.conflict_values.iter().chain(chunk.iter().flatten());
+
+                        rows += statement.execute(params).map_err(|e| Error::from_execute(sql, e))?;
                     }
                 }
             }
-            Err(err) => {
-                println!("Err: {:?}", err);
-                Err("Could not execute `INSERT`. Table might not exist, there is an issue with the query or the database is unavailable.")
-            }
         }
+
+        Ok(rows)
+    }
+}
+
+impl Queryable for InsertMany {
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        with_savepoint(connection, "rusq_insert_many", || self.handle_execute(connection))
     }
 
     fn get_type_name(&self) -> &'static str {
-        "INSERT"
+        "INSERT_MANY"
     }
 
     fn get_raw_sql(&self) -> &'_ str {
@@ -252,15 +909,8 @@ impl Queryable for Insert {
 }
 
 impl Queryable for Create {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
-        match connection.execute(&self.sql, NO_PARAMS) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                // TODO log error details somewhere.
-                // println!("Err: {:?}", err);
-                Err("Could not execute `CREATE`. Table might already exist or the database is unavailable.")
-            }
-        }
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        execute_sql(connection, &self.sql, NO_PARAMS)
     }
 
     fn get_type_name(&self) -> &'static str {
@@ -273,27 +923,15 @@ impl Queryable for Create {
 }
 
 impl Queryable for Update {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
-        match connection.execute(&self.sql, &self.values) {
-            Ok(_) => {
-                match &self.blobs {
-                    None => Ok(()),
-                    Some(blobs) => {
-                        let row_id = connection.last_insert_rowid();
-
-                        for blob in blobs {
-                            let mut b = connection.blob_open(DatabaseName::Main, blob.table.as_str(), blob.field.as_str(), row_id, false).unwrap();
-                            b.write_at(blob.data.as_slice(), 0);
-                        }
-
-                        Ok(())
-                    }
-                }
-            }
-            Err(err) => {
-                println!("Err: {:?}", err);
-                Err("Could not execute `UPDATE`. Table might not exist, there is an issue with the query or the database is unavailable.")
-            }
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        self.execute_returning(connection).map(|(rows, _)| rows)
+    }
+
+    fn execute_returning(&self, connection: &Connection) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        match &self.blobs {
+            None => execute_returning_sql(connection, &self.sql, &self.values, &self.returning),
+            // Run in a savepoint so a failed blob write doesn't leave rows with zeroed blobs.
+            Some(blobs) => with_savepoint(connection, "rusq_update", || self.execute_with_blobs(connection, blobs))
         }
     }
 
@@ -307,26 +945,14 @@ impl Queryable for Update {
 }
 
 impl Queryable for Delete {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        self.execute_returning(connection).map(|(rows, _)| rows)
+    }
+
+    fn execute_returning(&self, connection: &Connection) -> Result<(usize, Vec<ReturnedRow>), Error> {
         match &self.values {
-            None => {
-                match connection.execute(&self.sql, NO_PARAMS) {
-                    Ok(_) => Ok(()),
-                    Err(err) => {
-                        println!("Err: {:?}", err);
-                        Err("Could not execute `DELETE`. Table might not exist, there is an issue with the query or the database is unavailable.")
-                    }
-                }
-            }
-            Some(p) => {
-                match connection.execute(&self.sql, p) {
-                    Ok(_) => Ok(()),
-                    Err(err) => {
-                        println!("Err: {:?}", err);
-                        Err("Could not execute `DELETE`. Table might not exist, there is an issue with the query or the database is unavailable.")
-                    }
-                }
-            }
+            None => execute_returning_sql(connection, &self.sql, NO_PARAMS, &self.returning),
+            Some(p) => execute_returning_sql(connection, &self.sql, p, &self.returning)
         }
     }
 
@@ -340,25 +966,65 @@ impl Queryable for Delete {
 }
 
 impl Queryable for UpdateBlob {
-    fn execute(&self, connection: &Connection) -> Result<(), &'static str> {
-        match connection.execute(&self.sql, NO_PARAMS) {
-            Ok(_) => {
-                let row_id = self.row_id;
+    /// The blob and its checksum are written in a savepoint, so a failure leaves neither changed.
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        with_savepoint(connection, "rusq_update_blob", || {
+            let rows = execute_sql(connection, &self.sql, NO_PARAMS)?;
 
-                let mut b = connection.blob_open(DatabaseName::Main, self.table_name.as_str(), self.field_name.as_str(), self.row_id, false).unwrap();
-                b.write_at(self.data.as_slice(), 0);
+            self.blob.write(connection, self.row_id)?;
 
-                Ok(())
-            }
-            Err(err) => {
-                println!("Err: {:?}", err);
-                Err("Could not execute `UPDATE`. Table might not exist, there is an issue with the query or the database is unavailable.")
+            Ok(rows)
+        })
+    }
+
+    fn get_type_name(&self) -> &'static str {
+        "UPDATE_BLOB"
+    }
+
+    fn get_raw_sql(&self) -> &str {
+        self.sql.as_str()
+    }
+}
+
+impl Queryable for Savepoint {
+    /// Returns the total rows affected by the sub-transaction.
+    fn execute(&self, connection: &Connection) -> Result<usize, Error> {
+        self.execute_returning(connection).map(|(rows, _)| rows)
+    }
+
+    /// The returned rows of every query in the sub-transaction, in order.
+    /// If a query fails the sub-transaction is rolled back and `Error::Savepoint` is returned.
+    /// A nested `Savepoint` that rolled back on its own does not fail this one.
+    fn execute_returning(&self, connection: &Connection) -> Result<(usize, Vec<ReturnedRow>), Error> {
+        connection.execute_batch(&self.sql).map_err(|e| Error::from_execute(&self.sql, e))?;
+
+        let mut rows = 0;
+        let mut returned = Vec::new();
+
+        for (index, query) in self.queries.iter().enumerate() {
+            match query.execute_returning(connection) {
+                Ok((r, mut query_returned)) => {
+                    rows += r;
+                    returned.append(&mut query_returned);
+                }
+                Err(Error::Savepoint { .. }) => {}
+                Err(e) => {
+                    let sql = format!("ROLLBACK TO {0}; RELEASE {0};", self.name);
+                    connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+
+                    return Err(Error::Savepoint { index, source: Box::new(e) });
+                }
             }
         }
+
+        let sql = format!("RELEASE {};", self.name);
+        connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+
+        Ok((rows, returned))
     }
 
     fn get_type_name(&self) -> &'static str {
-        "UPDATE_BLOB"
+        "SAVEPOINT"
     }
 
     fn get_raw_sql(&self) -> &str {
@@ -366,9 +1032,498 @@ impl Queryable for UpdateBlob {
     }
 }
 
-fn vec_to_optional<T>(vec: Vec<T>) -> Option<Vec<T>> {
-    match vec.is_empty() {
-        true => None,
-        false => Some(vec)
+/// Prepare and execute a statement, keeping prepare and execution failures distinct.
+pub(crate) fn execute_sql<P>(connection: &Connection, sql: &str, params: P) -> Result<usize, Error> where P: IntoIterator, P::Item: ToSql {
+    let mut statement = connection.prepare(sql).map_err(|e| Error::from_prepare(sql, e))?;
+
+    statement.execute(params).map_err(|e| Error::from_execute(sql, e))
+}
+
+/// Execute a statement, with its `RETURNING` clause if it has one.
+fn execute_returning_sql<P>(connection: &Connection, sql: &str, params: P, returning: &Option<Returning>) -> Result<(usize, Vec<ReturnedRow>), Error> where P: IntoIterator, P::Item: ToSql {
+    match returning {
+        None => Ok((execute_sql(connection, sql, params)?, Vec::new())),
+        // The rows are written before they are mapped, so a mapping failure has to undo the write.
+        Some(returning) => with_savepoint(connection, "rusq_returning", || returning.query(connection, sql, params))
+    }
+}
+
+/// Run `f` inside a savepoint, rolling back anything it did if it fails.
+pub(crate) fn with_savepoint<T, F>(connection: &Connection, name: &str, f: F) -> Result<T, Error> where F: FnOnce() -> Result<T, Error> {
+    let sql = format!("SAVEPOINT {};", name);
+    connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+
+    match f() {
+        Ok(result) => {
+            let sql = format!("RELEASE {};", name);
+            connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+            Ok(result)
+        }
+        Err(e) => {
+            let sql = format!("ROLLBACK TO {0}; RELEASE {0};", name);
+            connection.execute_batch(&sql).map_err(|e| Error::from_execute(&sql, e))?;
+            Err(e)
+        }
+    }
+}
+
+fn write_blobs(connection: &Connection, blobs: &Vec<BlobValue>, row_id: i64) -> Result<(), Error> {
+    for blob in blobs {
+        blob.write(connection, row_id)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::WriteOutcome;
+    use crate::common::{ConflictUpdate, CriteriaItemValue};
+
+    fn connection() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER UNIQUE);").unwrap();
+        connection
+    }
+
+    fn get_values(connection: &Connection) -> Vec<i64> {
+        let mut statement = connection.prepare("SELECT v FROM t ORDER BY id").unwrap();
+        let rows = statement.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(|v| v.unwrap()).collect()
+    }
+
+    fn insert(v: i64) -> Query {
+        Insert::create("t", vec![Value::create("v", v)]).unwrap()
+    }
+
+    fn many_connection() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE m (a INTEGER UNIQUE, b TEXT, c INTEGER, d BLOB);").unwrap();
+        connection
+    }
+
+    fn many_rows(range: std::ops::Range<i64>) -> Vec<Vec<Value>> {
+        range.map(|a| vec![Value::create("a", a), Value::create("b", a.to_string()), Value::create("c", 0)]).collect()
+    }
+
+    fn get_ints(connection: &Connection, sql: &str) -> Vec<i64> {
+        let mut statement = connection.prepare(sql).unwrap();
+        let rows = statement.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(|v| v.unwrap()).collect()
+    }
+
+    fn blob_connection() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE b (id INTEGER PRIMARY KEY, name TEXT, data BLOB); INSERT INTO b (name, data) VALUES ('x', x'00'), ('y', x'00'), ('y', x'00');").unwrap();
+        connection
+    }
+
+    fn get_blobs(connection: &Connection) -> Vec<Vec<u8>> {
+        let mut statement = connection.prepare("SELECT data FROM b ORDER BY id").unwrap();
+        let rows = statement.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(|v| v.unwrap()).collect()
+    }
+
+    fn update_blob(name: &str, allow_multiple: bool) -> Query {
+        let values = vec![Value::create_blob("data", BlobRef::Memory(vec![1, 2, 3]))];
+        let criteria = Criteria::field("name").eq(String::from(name)).build().unwrap();
+
+        match allow_multiple {
+            true => Update::create_multiple("b", values, criteria).unwrap(),
+            false => Update::create("b", values, criteria).unwrap()
+        }
+    }
+
+    #[test]
+    fn savepoint_commits_every_query() {
+        let connection = connection();
+
+        let rows = Savepoint::create(vec![insert(1), insert(2)]).unwrap().execute(&connection).unwrap();
+
+        assert_eq!(rows, 2);
+        assert_eq!(get_values(&connection), vec![1, 2]);
+    }
+
+    #[test]
+    fn savepoint_rolls_back_atomically() {
+        let connection = connection();
+        connection.execute_batch("BEGIN;").unwrap();
+        insert(1).execute(&connection).unwrap();
+
+        let result = Savepoint::create(vec![insert(2), insert(3), insert(1)]).unwrap().execute(&connection);
+
+        assert!(matches!(result, Err(Error::Savepoint { index: 2, .. })));
+        assert!(!connection.is_autocommit());
+
+        connection.execute_batch("COMMIT;").unwrap();
+        assert_eq!(get_values(&connection), vec![1]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nested_savepoint_rolls_back_on_its_own() {
+        let connection = connection();
+
+        let inner = Savepoint::create(vec![insert(1), insert(1)]).unwrap();
+        let middle = Savepoint::create(vec![insert(2), inner]).unwrap();
+        let outer = Savepoint::create(vec![insert(3), middle, insert(4)]).unwrap();
+
+        outer.execute(&connection).unwrap();
+
+        assert_eq!(get_values(&connection), vec![3, 2, 4]);
+    }
+
+    #[test]
+    fn nested_savepoint_is_rolled_back_with_its_parent() {
+        let connection = connection();
+
+        let inner = Savepoint::create(vec![insert(2)]).unwrap();
+        let outer = Savepoint::create(vec![insert(1), inner, insert(1)]).unwrap();
+
+        let result = outer.execute(&connection);
+
+        assert!(matches!(result, Err(Error::Savepoint { index: 2, .. })));
+        assert!(get_values(&connection).is_empty());
+    }
+
+    #[test]
+    fn update_writes_blobs_to_the_matched_row() {
+        let connection = blob_connection();
+
+        let query = update_blob("x", false);
+
+        assert_eq!(query.get_raw_sql(), "UPDATE `b` SET `data` = ZEROBLOB(3) WHERE `name` = ?1;");
+        assert_eq!(query.execute(&connection).unwrap(), 1);
+        assert_eq!(get_blobs(&connection), vec![vec![1, 2, 3], vec![0], vec![0]]);
+    }
+
+    #[test]
+    fn update_fails_when_no_rows_match() {
+        let connection = blob_connection();
+
+        let result = update_blob("none", false).execute(&connection);
+
+        assert!(matches!(result, Err(Error::UnexpectedRowCount { matched: 0, .. })));
+        assert_eq!(get_blobs(&connection), vec![vec![0], vec![0], vec![0]]);
+    }
+
+    #[test]
+    fn update_writes_blobs_to_many_rows_only_when_allowed() {
+        let connection = blob_connection();
+
+        let result = update_blob("y", false).execute(&connection);
+        assert!(matches!(result, Err(Error::UnexpectedRowCount { matched: 2, .. })));
+        assert_eq!(get_blobs(&connection), vec![vec![0], vec![0], vec![0]]);
+
+        assert_eq!(update_blob("y", true).execute(&connection).unwrap(), 2);
+        assert_eq!(get_blobs(&connection), vec![vec![0], vec![1, 2, 3], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn insert_many_chunks_at_the_parameter_limit() {
+        // Three values a row fit 333 rows in a statement.
+        for count in &[1, 332, 333, 334, 666, 667, 1000] {
+            let connection = many_connection();
+            let query = Insert::create_many("m", many_rows(0..*count)).unwrap();
+
+            assert_eq!(query.get_raw_sql().matches('?').count(), 3 * (*count as usize).min(333), "{}", count);
+            assert_eq!(query.execute(&connection).unwrap(), *count as usize);
+            assert_eq!(get_ints(&connection, "SELECT a FROM m ORDER BY rowid"), (0..*count).collect::<Vec<i64>>());
+        }
+    }
+
+    #[test]
+    fn insert_many_chunks_after_conflict_parameters() {
+        let connection = many_connection();
+        connection.execute_batch("INSERT INTO m (a, c) VALUES (0, 0), (500, 0);").unwrap();
+
+        // The update's value is bound first, leaving room for 332 rows a statement.
+        let on_conflict = OnConflict::do_update(vec!["a"], vec![ConflictUpdate::value("c", -1)]);
+        let query = Insert::create_many_with_conflict("m", many_rows(0..700), on_conflict).unwrap();
+
+        assert_eq!(query.get_raw_sql().matches('?').count(), 1 + 3 * 332);
+        assert_eq!(query.execute(&connection).unwrap(), 700);
+        assert_eq!(get_ints(&connection, "SELECT count(*) FROM m"), vec![700]);
+        assert_eq!(get_ints(&connection, "SELECT a FROM m WHERE c = -1 ORDER BY a"), vec![0, 500]);
+    }
+
+    #[test]
+    fn insert_many_keeps_rows_in_order_across_groups() {
+        let connection = many_connection();
+
+        let mut rows = many_rows(0..2);
+        rows.push(vec![Value::create("a", 2)]);
+        rows.push(vec![Value::create("a", 3), Value::create_blob("d", BlobRef::Memory(vec![1, 2]))]);
+        rows.append(&mut many_rows(4..6));
+
+        assert_eq!(Insert::create_many("m", rows).unwrap().execute(&connection).unwrap(), 6);
+        assert_eq!(get_ints(&connection, "SELECT a FROM m ORDER BY rowid"), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(get_ints(&connection, "SELECT length(d) FROM m WHERE d IS NOT NULL"), vec![2]);
+    }
+
+    #[test]
+    fn insert_many_fails_as_a_whole() {
+        let connection = many_connection();
+
+        let mut rows = many_rows(0..400);
+        rows.append(&mut many_rows(399..400));
+
+        assert!(Insert::create_many("m", rows).unwrap().execute(&connection).is_err());
+        assert_eq!(get_ints(&connection, "SELECT count(*) FROM m"), vec![0]);
+    }
+
+    fn upsert(on_conflict: OnConflict) -> Query {
+        Insert::create_with_conflict("m", vec![Value::create("a", 1), Value::create("b", "new")], on_conflict).unwrap()
+    }
+
+    fn get_texts(connection: &Connection, sql: &str) -> Vec<String> {
+        let mut statement = connection.prepare(sql).unwrap();
+        let rows = statement.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(|v| v.unwrap()).collect()
+    }
+
+    #[test]
+    fn builds_conflict_clauses() {
+        assert_eq!(upsert(OnConflict::Ignore).get_raw_sql(), "INSERT OR IGNORE INTO `m` (`a`, `b`) VALUES (?1, ?2);");
+        assert_eq!(upsert(OnConflict::Replace).get_raw_sql(), "INSERT OR REPLACE INTO `m` (`a`, `b`) VALUES (?1, ?2);");
+        assert_eq!(upsert(OnConflict::do_nothing(Vec::<&str>::new())).get_raw_sql(), "INSERT INTO `m` (`a`, `b`) VALUES (?1, ?2) ON CONFLICT DO NOTHING;");
+        assert_eq!(upsert(OnConflict::do_nothing(vec!["a"])).get_raw_sql(), "INSERT INTO `m` (`a`, `b`) VALUES (?1, ?2) ON CONFLICT (`a`) DO NOTHING;");
+
+        // The update's values are bound before the row's.
+        let updates = vec![ConflictUpdate::excluded("b"), ConflictUpdate::value("c", 7), ConflictUpdate::raw("d", "NULL")];
+        assert_eq!(upsert(OnConflict::do_update(vec![Identifier::from("a")], updates)).get_raw_sql(),
+                   "INSERT INTO `m` (`a`, `b`) VALUES (?2, ?3) ON CONFLICT (`a`) DO UPDATE SET `b` = excluded.`b`, `c` = ?1, `d` = NULL;");
+    }
+
+    #[test]
+    fn rejects_invalid_conflict_clauses() {
+        let result = Insert::create_with_conflict("m", vec![Value::create("a", 1)], OnConflict::do_update(vec!["a"], Vec::new()));
+        assert!(matches!(result, Err(Error::InvalidQuery(_))));
+
+        let blob = vec![Value::create("a", 1), Value::create_blob("d", BlobRef::Memory(vec![1]))];
+        let result = Insert::create_with_conflict("m", blob, OnConflict::do_update(vec!["a"], vec![ConflictUpdate::excluded("d")]));
+        assert!(matches!(result, Err(Error::InvalidQuery(_))));
+
+        assert!(Insert::create_with_conflict("m", vec![Value::create("a", 1)], OnConflict::do_nothing(vec![""])).is_err());
+    }
+
+    #[test]
+    fn resolves_conflicts() {
+        let connection = many_connection();
+        connection.execute_batch("INSERT INTO m (a, b, c) VALUES (1, 'old', 0);").unwrap();
+
+        assert_eq!(upsert(OnConflict::Ignore).execute(&connection).unwrap(), 0);
+        assert_eq!(upsert(OnConflict::do_nothing(vec!["a"])).execute(&connection).unwrap(), 0);
+        assert_eq!(get_texts(&connection, "SELECT b FROM m"), vec!["old"]);
+
+        let updates = vec![ConflictUpdate::excluded("b"), ConflictUpdate::raw("c", "c + 1")];
+        assert_eq!(upsert(OnConflict::do_update(vec!["a"], updates)).execute(&connection).unwrap(), 1);
+        assert_eq!(get_texts(&connection, "SELECT b || c FROM m"), vec!["new1"]);
+
+        assert!(upsert(OnConflict::Abort).execute(&connection).is_err());
+    }
+
+    fn returning_a() -> Returning {
+        Returning::create(vec!["a"], |row| row.get::<_, i64>(0).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    fn get_outcome(connection: &Connection, query: Query) -> WriteOutcome {
+        let (rows_affected, returned) = query.execute_returning(connection).unwrap();
+
+        WriteOutcome {
+            rows_affected,
+            last_insert_rowid: connection.last_insert_rowid(),
+            returned,
+            rolled_back: None,
+        }
+    }
+
+    #[test]
+    fn returns_inserted_rows() {
+        let connection = many_connection();
+
+        let query = Insert::create_returning("m", vec![Value::create("a", 5)], returning_a()).unwrap();
+        assert_eq!(query.get_raw_sql(), "INSERT INTO `m` (`a`) VALUES (?1) RETURNING `a`;");
+
+        let mut outcome = get_outcome(&connection, query);
+        assert_eq!(outcome.rows_affected, 1);
+        assert_eq!(outcome.take_returned::<i64>().unwrap(), vec![5]);
+        assert!(outcome.returned.is_empty());
+    }
+
+    #[test]
+    fn returns_updated_and_deleted_rows() {
+        let connection = many_connection();
+        connection.execute_batch("INSERT INTO m (a, c) VALUES (1, 0), (2, 0), (3, 1);").unwrap();
+
+        let criteria = Criteria::field("c").eq(0).build().unwrap();
+        let query = Update::create_returning("m", vec![Value::create("b", "x")], criteria, returning_a()).unwrap();
+        assert_eq!(query.get_raw_sql(), "UPDATE `m` SET `b` = ?1 WHERE `c` = ?2 RETURNING `a`;");
+
+        let mut outcome = get_outcome(&connection, query);
+        let mut returned = outcome.take_returned::<i64>().unwrap();
+        returned.sort();
+        assert_eq!((outcome.rows_affected, returned), (2, vec![1, 2]));
+
+        let query = Delete::create_returning("m", Criteria::field("a").gt(1).build().unwrap(), Returning::all(|row| row.get::<_, i64>("a").map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))).unwrap();
+        assert_eq!(query.get_raw_sql(), "DELETE FROM `m` WHERE `a` > ?1 RETURNING *;");
+
+        let mut outcome = get_outcome(&connection, query);
+        let mut returned = outcome.take_returned::<i64>().unwrap();
+        returned.sort();
+        assert_eq!((outcome.rows_affected, returned), (2, vec![2, 3]));
+    }
+
+    #[test]
+    fn returns_nothing_when_no_rows_are_written() {
+        let connection = many_connection();
+
+        let query = Delete::create_returning("m", Criteria::field("a").eq(1).build().unwrap(), returning_a()).unwrap();
+        let mut outcome = get_outcome(&connection, query);
+
+        assert_eq!(outcome.rows_affected, 0);
+        assert!(outcome.take_returned::<i64>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_returned_rows_of_another_type() {
+        let connection = many_connection();
+
+        let query = Insert::create_returning("m", vec![Value::create("a", 5)], returning_a()).unwrap();
+        let mut outcome = get_outcome(&connection, query);
+
+        assert!(matches!(outcome.take_returned::<String>(), Err(Error::ReturnedType(_))));
+        assert_eq!(outcome.take_returned::<i64>().unwrap(), vec![5]);
+    }
+
+    fn select_connection() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+            CREATE TABLE orders (id INTEGER PRIMARY KEY, user_id INTEGER, total INTEGER);
+            INSERT INTO users VALUES (1, 'a'), (2, 'b'), (3, 'z');
+            INSERT INTO orders VALUES (1, 1, 5), (2, 1, 8), (3, 2, 200), (4, 3, 50), (5, 2, 1);").unwrap();
+        connection
+    }
+
+    fn select_rows<T, F>(connection: &Connection, sql: &str, params: Vec<BoxedValue>, f: F) -> Vec<T> where F: FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T> {
+        let mut statement = connection.prepare(sql).unwrap();
+        let rows = statement.query_map(params, f).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    fn column(table: &str, name: &str) -> Identifier {
+        Identifier::qualified(table, name).unwrap()
+    }
+
+    #[test]
+    fn selects_every_column_by_default() {
+        let (sql, params) = Select::create("users").build().unwrap();
+
+        assert_eq!(sql, "SELECT * FROM `users`");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn selects_with_joins_grouping_and_paging() {
+        let connection = select_connection();
+
+        let join = JoinCondition::eq(column("o", "user_id"), column("u", "id"))
+            .and(JoinCondition::criteria(Criteria::field(column("o", "total")).gt(1).build().unwrap()));
+
+        let (sql, params) = Select::create(SelectSource::table_as("users", "u"))
+            .columns(vec![column("u", "name")])
+            .column(SelectColumn::expression("sum(o.total)", "spent"))
+            .inner_join(SelectSource::table_as("orders", "o"), join)
+            .criteria(Criteria::field(column("u", "name")).ne("z").build().unwrap())
+            .group_by(vec![column("u", "name")])
+            .having(Criteria::field("spent").ge(10).build().unwrap())
+            .order_by("spent", Order::Descending)
+            .limit(5)
+            .offset(1)
+            .build()
+            .unwrap();
+
+        assert_eq!(sql, "SELECT `u`.`name`, sum(o.total) AS `spent` FROM `users` AS `u` \
+            INNER JOIN `orders` AS `o` ON `o`.`user_id` = `u`.`id` AND (`o`.`total` > ?1) \
+            WHERE `u`.`name` <> ?2 GROUP BY `u`.`name` HAVING `spent` >= ?3 ORDER BY `spent` DESC LIMIT 5 OFFSET 1");
+
+        let rows = select_rows(&connection, &sql, params, |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)));
+        assert_eq!(rows, vec![(String::from("a"), 13)]);
+    }
+
+    #[test]
+    fn numbers_placeholders_through_subqueries() {
+        let connection = select_connection();
+
+        let count = Select::create("orders")
+            .column(SelectColumn::expression("count(*)", "n"))
+            .criteria(Criteria::field(column("orders", "user_id")).operator(Operator::Equal, vec![CriteriaItemValue::Raw(String::from("users.id"))]).build().unwrap());
+        let big_spenders = Select::create("orders")
+            .columns(vec!["user_id"])
+            .criteria(Criteria::field("total").gt(100).build().unwrap());
+
+        let (sql, params) = Select::create("users")
+            .columns(vec!["name"])
+            .column(SelectColumn::subquery(count, "orders"))
+            .criteria(Criteria::field("id").in_select(big_spenders).and(Criteria::field("name").ne("x")).build().unwrap())
+            .build()
+            .unwrap();
+
+        assert_eq!(sql, "SELECT `name`, (SELECT count(*) AS `n` FROM `orders` WHERE `orders`.`user_id` = users.id) AS `orders` FROM `users` \
+            WHERE (`id` IN (SELECT `user_id` FROM `orders` WHERE `total` > ?1) AND `name` <> ?2)");
+
+        let rows = select_rows(&connection, &sql, params, |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)));
+        assert_eq!(rows, vec![(String::from("b"), 2)]);
+    }
+
+    #[test]
+    fn selects_from_a_subquery_with_a_left_join() {
+        let connection = select_connection();
+
+        let cheap = Select::create("orders").criteria(Criteria::field("total").lt(5).build().unwrap());
+
+        let (sql, params) = Select::create(SelectSource::subquery(cheap, "cheap"))
+            .distinct()
+            .columns(vec![column("users", "name")])
+            .left_join("users", JoinCondition::eq(column("users", "id"), column("cheap", "user_id")))
+            .build()
+            .unwrap();
+
+        assert_eq!(sql, "SELECT DISTINCT `users`.`name` FROM (SELECT * FROM `orders` WHERE `total` < ?1) AS `cheap` \
+            LEFT JOIN `users` ON `users`.`id` = `cheap`.`user_id`");
+
+        let rows = select_rows(&connection, &sql, params, |row| row.get::<_, String>(0));
+        assert_eq!(rows, vec![String::from("b")]);
+    }
+
+    #[test]
+    fn rejects_invalid_join_operators() {
+        let join = JoinCondition::compare("a", Operator::IsNull, "b");
+
+        assert!(matches!(Select::create("users").inner_join("orders", join).build(), Err(Error::InvalidQuery(_))));
+    }
+
+    #[test]
+    fn reads_file_blobs_when_executed() {
+        let connection = many_connection();
+        let path = std::env::temp_dir().join(format!("rusq_file_blob_{}", Uuid::new_v4().to_simple()));
+        std::fs::write(&path, b"abc").unwrap();
+
+        let insert = || Insert::create("m", vec![Value::create("a", 1), Value::create_blob("d", BlobRef::File(path.clone().into_boxed_path()))]).unwrap();
+
+        let first = insert();
+        let second = insert();
+
+        // Same size, so the reserved space still fits.
+        std::fs::write(&path, b"xyz").unwrap();
+        first.execute(&connection).unwrap();
+        assert_eq!(get_texts(&connection, "SELECT CAST(d AS TEXT) FROM m"), vec!["xyz"]);
+
+        std::fs::remove_file(&path).unwrap();
+        connection.execute_batch("DELETE FROM m;").unwrap();
+
+        assert!(matches!(second.execute(&connection), Err(Error::BlobIo { .. })));
+        assert_eq!(get_ints(&connection, "SELECT count(*) FROM m"), vec![0]);
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender, Receiver, RecvError, RecvTimeoutError, TrySendError};
+use std::time::{Duration, Instant};
+use crate::WriteRequest;
+use crate::error::Error;
+
+/// A snapshot of the `DbWriter` queue.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueMetrics {
+    /// Requests posted but not yet picked up by the `DbWriter`,
+    /// including any waiting for space in a bounded queue.
+    pub depth: usize,
+    /// The largest `depth` seen since the context was created.
+    pub high_water_mark: usize,
+    /// The most requests the queue will hold, `None` if it is unbounded.
+    pub capacity: Option<usize>,
+}
+
+struct QueueCounters {
+    depth: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    capacity: Option<usize>,
+    /// The number of messages received from a bounded queue, so `send_timeout` can wait for space to be freed.
+    received: Mutex<u64>,
+    space_freed: Condvar,
+}
+
+/// What the `DbWriter` receives. Shutdown can only be sent by the crate, not posted by a `DataWriter`.
+pub(crate) enum QueueMessage {
+    Request(WriteRequest),
+    Shutdown,
+}
+
+enum QueueSender {
+    Unbounded(Sender<QueueMessage>),
+    Bounded(SyncSender<QueueMessage>),
+}
+
+/// The sending half of the `DbWriter` queue, shared by every `DataWriter`.
+#[derive(Clone)]
+pub(crate) struct WriteQueue {
+    sender: QueueSender,
+    counters: Arc<QueueCounters>,
+}
+
+/// The receiving half of the `DbWriter` queue.
+pub(crate) struct WriteQueueReceiver {
+    receiver: Receiver<QueueMessage>,
+    counters: Arc<QueueCounters>,
+}
+
+/// Create a queue for the `DbWriter`, bounded to `capacity` requests if supplied.
+pub(crate) fn create(capacity: Option<usize>) -> (WriteQueue, WriteQueueReceiver) {
+    let (sender, receiver) = match capacity {
+        None => {
+            let (sender, receiver) = mpsc::channel();
+            (QueueSender::Unbounded(sender), receiver)
+        }
+        Some(capacity) => {
+            let (sender, receiver) = mpsc::sync_channel(capacity);
+            (QueueSender::Bounded(sender), receiver)
+        }
+    };
+
+    let counters = Arc::new(QueueCounters {
+        depth: AtomicUsize::new(0),
+        high_water_mark: AtomicUsize::new(0),
+        capacity,
+        received: Mutex::new(0),
+        space_freed: Condvar::new(),
+    });
+
+    (WriteQueue { sender, counters: counters.clone() }, WriteQueueReceiver { receiver, counters })
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        match self {
+            QueueSender::Unbounded(sender) => QueueSender::Unbounded(sender.clone()),
+            QueueSender::Bounded(sender) => QueueSender::Bounded(sender.clone())
+        }
+    }
+}
+
+impl WriteQueue {
+    /// Send the request, blocking while a bounded queue is full.
+    pub(crate) fn send(&self, request: WriteRequest) -> Result<(), Error> {
+        self.send_message(QueueMessage::Request(request))
+    }
+
+    /// Ask the `DbWriter` to stop once every request already queued has been handled,
+    /// waiting up to `timeout` for space in a bounded queue.
+    pub(crate) fn send_shutdown(&self, timeout: Duration) -> Result<(), Error> {
+        self.send_message_timeout(QueueMessage::Shutdown, timeout)
+    }
+
+    fn send_message(&self, message: QueueMessage) -> Result<(), Error> {
+        self.counters.increment();
+
+        let result = match &self.sender {
+            QueueSender::Unbounded(sender) => sender.send(message).map_err(|_| Error::ChannelClosed),
+            QueueSender::Bounded(sender) => sender.send(message).map_err(|_| Error::ChannelClosed)
+        };
+
+        self.counters.complete(&result);
+        result
+    }
+
+    /// Send the request without blocking, failing with `Error::QueueFull` if a bounded queue is full.
+    pub(crate) fn try_send(&self, request: WriteRequest) -> Result<(), Error> {
+        self.counters.increment();
+
+        let message = QueueMessage::Request(request);
+
+        let result = match &self.sender {
+            QueueSender::Unbounded(sender) => sender.send(message).map_err(|_| Error::ChannelClosed),
+            QueueSender::Bounded(sender) => match sender.try_send(message) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(Error::QueueFull),
+                Err(TrySendError::Disconnected(_)) => Err(Error::ChannelClosed)
+            }
+        };
+
+        self.counters.complete(&result);
+        result
+    }
+
+    /// Send the request, waiting up to `timeout` for space in a bounded queue.
+    pub(crate) fn send_timeout(&self, request: WriteRequest, timeout: Duration) -> Result<(), Error> {
+        self.send_message_timeout(QueueMessage::Request(request), timeout)
+    }
+
+    fn send_message_timeout(&self, message: QueueMessage, timeout: Duration) -> Result<(), Error> {
+        let sender = match &self.sender {
+            QueueSender::Unbounded(_) => return self.send_message(message),
+            QueueSender::Bounded(sender) => sender
+        };
+
+        let deadline = Instant::now() + timeout;
+        let mut message = message;
+
+        self.counters.increment();
+
+        let result = loop {
+            // Read before trying, so space freed after a failed attempt isn't missed.
+            let received = self.counters.get_received();
+
+            match sender.try_send(message) {
+                Ok(_) => break Ok(()),
+                Err(TrySendError::Disconnected(_)) => break Err(Error::ChannelClosed),
+                Err(TrySendError::Full(m)) => {
+                    if !self.counters.wait_for_space(received, deadline) {
+                        break Err(Error::Timeout);
+                    }
+
+                    message = m;
+                }
+            }
+        };
+
+        self.counters.complete(&result);
+        result
+    }
+
+    pub(crate) fn get_metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.counters.depth.load(Ordering::SeqCst),
+            high_water_mark: self.counters.high_water_mark.load(Ordering::SeqCst),
+            capacity: self.counters.capacity,
+        }
+    }
+}
+
+impl WriteQueueReceiver {
+    pub(crate) fn recv(&self) -> Result<QueueMessage, RecvError> {
+        let message = self.receiver.recv()?;
+        self.counters.received();
+        Ok(message)
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<QueueMessage, RecvTimeoutError> {
+        let message = self.receiver.recv_timeout(timeout)?;
+        self.counters.received();
+        Ok(message)
+    }
+}
+
+impl QueueCounters {
+    // The depth is incremented before sending, so the writer can never receive
+    // (and decrement) a request that hasn't been counted yet.
+    fn increment(&self) {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Only requests that made it onto the queue count towards the high water mark.
+    fn complete(&self, result: &Result<(), Error>) {
+        match result {
+            Ok(_) => {
+                self.high_water_mark.fetch_max(self.depth.load(Ordering::SeqCst), Ordering::SeqCst);
+            }
+            Err(_) => self.decrement()
+        }
+    }
+
+    fn decrement(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// A message was taken off the queue, waking any `send_timeout` waiting for space.
+    fn received(&self) {
+        self.decrement();
+
+        if self.capacity.is_some() {
+            let mut received = self.received.lock().unwrap_or_else(|e| e.into_inner());
+            *received += 1;
+            self.space_freed.notify_all();
+        }
+    }
+
+    fn get_received(&self) -> u64 {
+        *self.received.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until a message has been received since `seen`, returning `false` if `deadline` passes first.
+    fn wait_for_space(&self, seen: u64, deadline: Instant) -> bool {
+        let mut received = self.received.lock().unwrap_or_else(|e| e.into_inner());
+
+        while *received == seen {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return false;
+            }
+
+            received = match self.space_freed.wait_timeout(received, deadline - now) {
+                Ok((received, _)) => received,
+                Err(e) => e.into_inner().0
+            };
+        }
+
+        true
+    }
+}
@@ -0,0 +1,707 @@
+#[cfg(feature = "column_metadata")]
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+#[cfg(feature = "column_metadata")]
+use std::os::raw::c_char;
+#[cfg(feature = "column_metadata")]
+use std::ptr;
+use std::sync::Arc;
+#[cfg(feature = "column_metadata")]
+use rusqlite::ffi;
+use rusqlite::{Connection, Row, Rows, Statement, ToSql};
+use rusqlite::types::ValueRef;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::de::value::SeqDeserializer;
+use serde::forward_to_deserialize_any;
+use crate::common::BoxedValue;
+use crate::error::Error;
+use crate::identifier::Identifier;
+
+/// A type that can be built from a row, and knows which fields to select for it.
+/// Any `T: Deserialize` is a `FromRow`, its fields are matched to the row's columns by name.
+/// Implement it directly for types that don't derive `Deserialize`.
+pub trait FromRow: Sized {
+    /// The fields to select, i.e. for `DataReader::get_as`.
+    fn get_field_names() -> Result<Vec<Identifier>, Error>;
+
+    /// Map a row, the signature of a `DataReader::get` mapper.
+    fn from_row(row: &Row<'_>) -> Result<Self, io::Error>;
+}
+
+impl<T> FromRow for T where T: DeserializeOwned {
+    /// The struct's field names, after any `#[serde(rename)]`. Skipped fields aren't selected.
+    /// Fails for structs with `#[serde(alias)]` fields, as their names can't be told apart.
+    fn get_field_names() -> Result<Vec<Identifier>, Error> {
+        let mut fields = None;
+
+        // Deserializing stops as soon as the struct hands over its field names.
+        let _ = T::deserialize(FieldNames(&mut fields));
+
+        let fields = match fields {
+            Some(fields) => fields,
+            None => return Err(Error::InvalidQuery("Only structs have field names to select"))
+        };
+
+        // The names include any `#[serde(alias)]`, which can't be told apart from the field they belong to.
+        // Field indexes past the last field are ignored, so they show whether there are fewer fields than names.
+        for index in 0..fields.len() {
+            let mut ignored = false;
+            let _ = T::deserialize(FieldProbe { index: index as u64, ignored: &mut ignored });
+
+            if ignored {
+                return Err(Error::InvalidQuery("Structs with `#[serde(alias)]` fields can't be selected, rename the field instead"));
+            }
+        }
+
+        Ok(fields.iter().map(|field| Identifier::from(*field)).collect())
+    }
+
+    fn from_row(row: &Row<'_>) -> Result<T, io::Error> {
+        T::deserialize(RowDeserializer { row }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// An owned SQL value, for rows whose shape isn't known ahead of time.
+/// This is rusqlite's `Value`, named so it isn't confused with `common::Value`.
+pub use rusqlite::types::Value as SqlValue;
+
+/// A result column and, with the `column_metadata` feature, where it comes from.
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    name: String,
+    declared_type: Option<String>,
+    #[cfg(feature = "column_metadata")]
+    table: Option<Identifier>,
+    #[cfg(feature = "column_metadata")]
+    origin_name: Option<String>,
+}
+
+/// A row as ordered column name and value pairs.
+/// The column names are shared by every row of a result.
+#[derive(Debug, Clone)]
+pub struct DynamicRow {
+    columns: Arc<Vec<String>>,
+    values: Vec<SqlValue>,
+}
+
+/// The rows of a dynamic query and their columns.
+#[derive(Debug, Clone)]
+pub struct DynamicRows {
+    columns: Vec<ColumnInfo>,
+    rows: Vec<DynamicRow>,
+}
+
+/// Blobs become arrays of bytes, as `serde_json` writes a `Vec<u8>`.
+/// Reals that aren't finite become `null`.
+pub fn to_json(value: &SqlValue) -> serde_json::Value {
+    match value {
+        SqlValue::Null => serde_json::Value::Null,
+        SqlValue::Integer(i) => serde_json::Value::from(*i),
+        SqlValue::Real(f) => serde_json::Number::from_f64(*f).map_or(serde_json::Value::Null, serde_json::Value::Number),
+        SqlValue::Text(s) => serde_json::Value::from(s.as_str()),
+        SqlValue::Blob(bytes) => serde_json::Value::from(bytes.clone()),
+    }
+}
+
+/// Copy a column value. Unlike rusqlite's conversion, text that isn't valid UTF-8 is replaced rather than panicking.
+fn to_owned_value(value: ValueRef<'_>) -> SqlValue {
+    match value {
+        ValueRef::Null => SqlValue::Null,
+        ValueRef::Integer(i) => SqlValue::Integer(i),
+        ValueRef::Real(f) => SqlValue::Real(f),
+        ValueRef::Text(bytes) => SqlValue::Text(String::from_utf8_lossy(bytes).into_owned()),
+        ValueRef::Blob(bytes) => SqlValue::Blob(bytes.to_vec()),
+    }
+}
+
+impl ColumnInfo {
+    /// The name of the column in the result, i.e. its alias.
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The type the column was declared with, `None` for expressions.
+    pub fn get_declared_type(&self) -> Option<&str> {
+        self.declared_type.as_deref()
+    }
+
+    /// The table the column comes from, `None` for expressions.
+    /// Tables in attached databases are qualified with the database name.
+    #[cfg(feature = "column_metadata")]
+    pub fn get_table(&self) -> Option<&Identifier> {
+        self.table.as_ref()
+    }
+
+    /// The name of the column in its table, `None` for expressions.
+    #[cfg(feature = "column_metadata")]
+    pub fn get_origin_name(&self) -> Option<&str> {
+        self.origin_name.as_deref()
+    }
+}
+
+impl DynamicRow {
+    pub(crate) fn create(columns: Arc<Vec<String>>, row: &Row<'_>) -> DynamicRow {
+        let values = (0..columns.len()).map(|i| to_owned_value(row.get_raw(i))).collect();
+
+        DynamicRow {
+            columns,
+            values,
+        }
+    }
+
+    pub fn get_column_names(&self) -> &[String] {
+        self.columns.as_slice()
+    }
+
+    pub fn get_values(&self) -> &[SqlValue] {
+        self.values.as_slice()
+    }
+
+    /// The value of the first column named `name`, ignoring ASCII case as SQLite does.
+    pub fn get(&self, name: &str) -> Option<&SqlValue> {
+        self.columns.iter().position(|column| column.eq_ignore_ascii_case(name)).map(|i| &self.values[i])
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<&SqlValue> {
+        self.values.get(index)
+    }
+
+    /// The column name and value pairs, in column order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SqlValue)> {
+        self.columns.iter().map(|column| column.as_str()).zip(self.values.iter())
+    }
+
+    /// The row as an object keyed by column name. The keys aren't kept in column order,
+    /// and a repeated column name keeps only its last value, so use `iter` when either matters.
+    pub fn to_json(&self) -> serde_json::Value {
+        let object: serde_json::Map<String, serde_json::Value> = self.iter()
+            .map(|(column, value)| (String::from(column), to_json(value)))
+            .collect();
+
+        serde_json::Value::Object(object)
+    }
+}
+
+impl DynamicRows {
+    pub fn get_columns(&self) -> &[ColumnInfo] {
+        self.columns.as_slice()
+    }
+
+    pub fn get_rows(&self) -> &[DynamicRow] {
+        self.rows.as_slice()
+    }
+
+    pub fn into_rows(self) -> Vec<DynamicRow> {
+        self.rows
+    }
+
+    /// The rows as an array of objects, see `DynamicRow::to_json`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Array(self.rows.iter().map(|row| row.to_json()).collect())
+    }
+}
+
+/// Read the columns of `sql`, then run it with `params` and read every row.
+pub(crate) fn query_dynamic<P>(connection: &Connection, sql: &str, params: P) -> Result<DynamicRows, Error> where P: IntoIterator, P::Item: ToSql {
+    let mut statement = connection.prepare(sql).map_err(|e| Error::from_prepare(sql, e))?;
+    let columns = get_column_info(connection, &statement, sql);
+    let names = Arc::new(columns.iter().map(|column| column.name.clone()).collect::<Vec<String>>());
+
+    let mut rows = statement.query(params).map_err(|e| Error::from_execute(sql, e))?;
+    let mut result = Vec::new();
+
+    while let Some(row) = rows.next().map_err(|e| Error::from_execute(sql, e))? {
+        result.push(DynamicRow::create(names.clone(), row));
+    }
+
+    Ok(DynamicRows {
+        columns,
+        rows: result,
+    })
+}
+
+fn get_column_info(connection: &Connection, statement: &Statement<'_>, sql: &str) -> Vec<ColumnInfo> {
+    #[cfg(feature = "column_metadata")]
+    let mut origins = get_column_origins(connection, sql).into_iter();
+    #[cfg(not(feature = "column_metadata"))]
+    let _ = (connection, sql);
+
+    statement.columns().into_iter().map(|column| {
+        #[cfg(feature = "column_metadata")]
+        let (table, origin_name) = origins.next().unwrap_or((None, None));
+
+        ColumnInfo {
+            name: String::from(column.name()),
+            declared_type: column.decl_type().map(String::from),
+            #[cfg(feature = "column_metadata")]
+            table,
+            #[cfg(feature = "column_metadata")]
+            origin_name,
+        }
+    }).collect()
+}
+
+/// rusqlite doesn't expose where a column comes from, so `sql` is prepared again through the C API to read it.
+/// This needs SQLite built with `SQLITE_ENABLE_COLUMN_METADATA`, as the bundled build and most distributions are.
+/// Empty if the statement can't be prepared, in which case only the names and declared types are known.
+#[cfg(feature = "column_metadata")]
+fn get_column_origins(connection: &Connection, sql: &str) -> Vec<(Option<Identifier>, Option<String>)> {
+    let sql = match CString::new(sql) {
+        Ok(sql) => sql,
+        Err(_) => return Vec::new()
+    };
+
+    // Safety: the statement is only used here and finalized before returning,
+    // and every string SQLite returns is copied before then.
+    unsafe {
+        let mut handle = ptr::null_mut();
+
+        if ffi::sqlite3_prepare_v2(connection.handle(), sql.as_ptr(), -1, &mut handle, ptr::null_mut()) != ffi::SQLITE_OK || handle.is_null() {
+            ffi::sqlite3_finalize(handle);
+            return Vec::new();
+        }
+
+        let mut origins = Vec::new();
+
+        for i in 0..ffi::sqlite3_column_count(handle) {
+            let table = match (copy_str(ffi::sqlite3_column_database_name(handle, i)), copy_str(ffi::sqlite3_column_table_name(handle, i))) {
+                (_, None) => None,
+                (Some(database), Some(table)) if database != "main" => Identifier::qualified(database, table).ok(),
+                (_, Some(table)) => Some(Identifier::from(table)),
+            };
+
+            origins.push((table, copy_str(ffi::sqlite3_column_origin_name(handle, i))));
+        }
+
+        ffi::sqlite3_finalize(handle);
+
+        origins
+    }
+}
+
+/// Copy a string owned by SQLite, `None` if it is null.
+#[cfg(feature = "column_metadata")]
+unsafe fn copy_str(s: *const c_char) -> Option<String> {
+    match s.is_null() {
+        true => None,
+        false => Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+/// A prepared select whose rows are read lazily, see `DataReader::stream`.
+/// The statement stays prepared, so it can be iterated again.
+pub struct RowStream<'a> {
+    sql: String,
+    statement: Statement<'a>,
+    params: Vec<BoxedValue>,
+}
+
+/// Mapped rows, read one at a time from a `RowStream`.
+/// A mapper error is returned for its row and iteration can carry on, a failure reading the rows ends it.
+/// Drop the iterator or stop calling `next` to stop early, the rest of the rows are never read.
+pub struct MappedRowIter<'s, F> {
+    sql: &'s str,
+    rows: Rows<'s>,
+    mapper: F,
+    done: bool,
+}
+
+impl<'a> RowStream<'a> {
+    pub(crate) fn create(connection: &'a Connection, sql: String, params: Vec<BoxedValue>) -> Result<RowStream<'a>, Error> {
+        let statement = connection.prepare(&sql).map_err(|e| Error::from_prepare(&sql, e))?;
+
+        Ok(RowStream {
+            sql,
+            statement,
+            params,
+        })
+    }
+
+    /// Run the statement and map its rows as they are read.
+    pub fn iter<T, F>(&mut self, mapper: F) -> Result<MappedRowIter<'_, F>, Error> where F: FnMut(&Row<'_>) -> Result<T, io::Error> {
+        let RowStream { sql, statement, params } = self;
+        let rows = statement.query(params.iter()).map_err(|e| Error::from_execute(sql, e))?;
+
+        Ok(MappedRowIter {
+            sql: sql.as_str(),
+            rows,
+            mapper,
+            done: false,
+        })
+    }
+
+    pub fn get_sql(&self) -> &str {
+        self.sql.as_str()
+    }
+}
+
+impl<'s, T, F> Iterator for MappedRowIter<'s, F> where F: FnMut(&Row<'_>) -> Result<T, io::Error> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Result<T, Error>> {
+        if self.done {
+            return None;
+        }
+
+        match self.rows.next() {
+            Ok(Some(row)) => Some((self.mapper)(row).map_err(|e| Error::Mapping { sql: String::from(self.sql), source: e })),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(Error::from_execute(self.sql, e)))
+            }
+        }
+    }
+}
+
+/// Why a row couldn't be deserialized, i.e. a missing column or a column of the wrong type.
+#[derive(Debug)]
+struct RowError(String);
+
+/// Deserializes a row as a map of column names to values, or as a sequence of values for tuples.
+struct RowDeserializer<'a, 'b> {
+    row: &'a Row<'b>,
+}
+
+/// Deserializes a single column value.
+struct ValueDeserializer<'a> {
+    value: ValueRef<'a>,
+}
+
+struct RowMap<'a, 'b> {
+    row: &'a Row<'b>,
+    index: usize,
+}
+
+struct RowSeq<'a, 'b> {
+    row: &'a Row<'b>,
+    index: usize,
+}
+
+/// Captures the field names of a struct without deserializing anything.
+struct FieldNames<'a>(&'a mut Option<&'static [&'static str]>);
+
+/// Hands a struct the field at `index`, noting if the struct ignores it.
+struct FieldProbe<'a> {
+    index: u64,
+    ignored: &'a mut bool,
+}
+
+/// The value of the probed field, which only records whether it is ignored.
+struct FieldProbeValue<'a>(&'a mut bool);
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RowError {}
+
+impl de::Error for RowError {
+    fn custom<T>(message: T) -> Self where T: fmt::Display {
+        RowError(message.to_string())
+    }
+}
+
+impl<'a, 'b> RowDeserializer<'a, 'b> {
+    fn get_column_name(row: &Row<'_>, index: usize) -> Result<String, RowError> {
+        row.column_name(index).map(String::from).map_err(|e| RowError(e.to_string()))
+    }
+}
+
+impl<'de, 'a, 'b> Deserializer<'de> for RowDeserializer<'a, 'b> {
+    type Error = RowError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_map(RowMap { row: self.row, index: 0 })
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_seq(RowSeq { row: self.row, index: 0 })
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a, 'b> MapAccess<'de> for RowMap<'a, 'b> {
+    type Error = RowError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, RowError> where K: DeserializeSeed<'de> {
+        match self.index < self.row.column_count() {
+            true => {
+                let name = RowDeserializer::get_column_name(self.row, self.index)?;
+                seed.deserialize(name.into_deserializer()).map(Some)
+            }
+            false => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, RowError> where V: DeserializeSeed<'de> {
+        let name = RowDeserializer::get_column_name(self.row, self.index)?;
+        let value = self.row.get_raw(self.index);
+        self.index += 1;
+
+        seed.deserialize(ValueDeserializer { value }).map_err(|e| RowError(format!("Column `{}`: {}", name, e)))
+    }
+}
+
+impl<'de, 'a, 'b> SeqAccess<'de> for RowSeq<'a, 'b> {
+    type Error = RowError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, RowError> where T: DeserializeSeed<'de> {
+        match self.index < self.row.column_count() {
+            true => {
+                let index = self.index;
+                let value = self.row.get_raw(index);
+                self.index += 1;
+
+                seed.deserialize(ValueDeserializer { value }).map(Some).map_err(|e| RowError(format!("Column {}: {}", index, e)))
+            }
+            false => Ok(None)
+        }
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = RowError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        match self.value {
+            ValueRef::Null => visitor.visit_unit(),
+            ValueRef::Integer(i) => visitor.visit_i64(i),
+            ValueRef::Real(f) => visitor.visit_f64(f),
+            ValueRef::Text(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_str(s),
+                Err(e) => Err(RowError(e.to_string()))
+            },
+            ValueRef::Blob(bytes) => visitor.visit_bytes(bytes),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        match self.value {
+            ValueRef::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    /// SQLite has no boolean type, they are stored as `0` and `1`.
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        match self.value {
+            ValueRef::Integer(i) => visitor.visit_bool(i != 0),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    /// Blobs deserialize into `Vec<u8>`, which expects a sequence rather than bytes.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        match self.value {
+            ValueRef::Blob(bytes) => visitor.visit_seq(SeqDeserializer::new(bytes.iter().copied())),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Unit variants are stored as their name.
+    fn deserialize_enum<V>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        match self.value {
+            ValueRef::Text(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_enum(String::from(s).into_deserializer()),
+                Err(e) => Err(RowError(e.to_string()))
+            },
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = RowError;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        Err(RowError(String::from("Not a struct")))
+    }
+
+    fn deserialize_struct<V>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        *self.0 = Some(fields);
+        Err(RowError(String::from("Field names captured")))
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for FieldProbe<'a> {
+    type Error = RowError;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        Err(RowError(String::from("Not a struct")))
+    }
+
+    fn deserialize_struct<V>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_map(self)
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a> MapAccess<'de> for FieldProbe<'a> {
+    type Error = RowError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, RowError> where K: DeserializeSeed<'de> {
+        seed.deserialize(self.index.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, RowError> where V: DeserializeSeed<'de> {
+        seed.deserialize(FieldProbeValue(self.ignored))
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for FieldProbeValue<'a> {
+    type Error = RowError;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        Err(RowError(String::from("Field probed")))
+    }
+
+    fn deserialize_ignored_any<V>(self, _visitor: V) -> Result<V::Value, RowError> where V: Visitor<'de> {
+        *self.0 = true;
+        Err(RowError(String::from("Field probed")))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::NO_PARAMS;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Item {
+        id: i64,
+        #[serde(rename = "title")]
+        name: String,
+        note: Option<String>,
+        #[serde(skip)]
+        cached: bool,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Aliased {
+        #[serde(alias = "bee")]
+        b: i64,
+    }
+
+    fn get_row<T: FromRow>(sql: &str) -> Result<T, io::Error> {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.query_row(sql, NO_PARAMS, |row| Ok(T::from_row(row))).unwrap()
+    }
+
+    fn get_names<T: FromRow>() -> Result<Vec<String>, Error> {
+        T::get_field_names()?.iter().map(|field| field.get_sql()).collect()
+    }
+
+    #[test]
+    fn selects_renamed_fields_and_skips_skipped_ones() {
+        assert_eq!(get_names::<Item>().unwrap(), vec!["`id`", "`title`", "`note`"]);
+    }
+
+    #[test]
+    fn rejects_aliased_fields() {
+        assert!(matches!(get_names::<Aliased>(), Err(Error::InvalidQuery(_))));
+
+        // They can still be mapped from rows selected some other way.
+        assert_eq!(get_row::<Aliased>("SELECT 2 AS bee").unwrap().b, 2);
+    }
+
+    #[test]
+    fn rejects_non_structs() {
+        assert!(matches!(get_names::<i64>(), Err(Error::InvalidQuery(_))));
+    }
+
+    #[test]
+    fn deserializes_by_column_name() {
+        let item: Item = get_row("SELECT 'a' AS note, 'b' AS title, 1 AS id").unwrap();
+
+        assert_eq!(item, Item { id: 1, name: String::from("b"), note: Some(String::from("a")), cached: false });
+    }
+
+    #[test]
+    fn deserializes_null_as_none() {
+        let item: Item = get_row("SELECT 1 AS id, 'b' AS title, NULL AS note").unwrap();
+
+        assert_eq!(item.note, None);
+    }
+
+    #[test]
+    fn fails_on_type_mismatch() {
+        let error = get_row::<Item>("SELECT 'x' AS id, 'b' AS title, NULL AS note").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_declared_types() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, name VARCHAR(20)); INSERT INTO t VALUES (1, 'a');").unwrap();
+
+        let rows = query_dynamic(&connection, "SELECT name AS n, id, count(*) AS c FROM t", NO_PARAMS).unwrap();
+        let columns: Vec<(&str, Option<&str>)> = rows.get_columns().iter().map(|c| (c.get_name(), c.get_declared_type())).collect();
+
+        assert_eq!(columns, vec![("n", Some("VARCHAR(20)")), ("id", Some("INTEGER")), ("c", None)]);
+    }
+
+    #[test]
+    fn fails_on_missing_columns() {
+        assert!(get_row::<Item>("SELECT 1 AS id").is_err());
+    }
+}
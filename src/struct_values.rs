@@ -0,0 +1,404 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use serde::ser::{self, Impossible, Serialize, SerializeSeq, SerializeStruct, SerializeTuple, Serializer};
+use crate::common::{BlobRef, Value};
+use crate::error::Error;
+use crate::row::SqlValue;
+
+/// How the fields of a struct map to columns in `Insert::from_struct` and `Update::from_struct`.
+/// Fields are named as `serde` names them, so `#[serde(skip)]` and `#[serde(rename)]` work as well.
+#[derive(Debug, Clone, Default)]
+pub struct StructOptions {
+    skip: HashSet<String>,
+    rename: HashMap<String, String>,
+    blobs: HashSet<String>,
+}
+
+impl StructOptions {
+    pub fn create() -> StructOptions {
+        StructOptions::default()
+    }
+
+    /// Leave `field` out, i.e. an `id` generated by the database.
+    pub fn skip<T>(mut self, field: T) -> StructOptions where T : Into<String> {
+        self.skip.insert(field.into());
+        self
+    }
+
+    /// Store `field` in `column`.
+    pub fn rename<T, U>(mut self, field: T, column: U) -> StructOptions where T : Into<String>, U : Into<String> {
+        self.rename.insert(field.into(), column.into());
+        self
+    }
+
+    /// Write `field` as a blob, streamed in after the row is written.
+    /// The field can be bytes, or a string holding the path of a file to read the blob from.
+    pub fn blob<T>(mut self, field: T) -> StructOptions where T : Into<String> {
+        self.blobs.insert(field.into());
+        self
+    }
+}
+
+/// Convert the fields of a struct to values, one per column.
+/// Fields must be plain values: numbers, strings, bools, bytes, unit enum variants (stored as their name)
+/// or options of those. `None` is stored as `NULL`.
+/// Bytes are anything serialized with `serialize_bytes`, i.e. `serde_bytes`. A `Vec<u8>` or `[u8; N]`,
+/// which serde serializes as a sequence, is only accepted in a field marked with `StructOptions::blob`.
+pub fn to_values<T>(value: &T, options: &StructOptions) -> Result<Vec<Value>, Error> where T : Serialize {
+    value.serialize(StructSerializer { options }).map_err(|e| Error::Serialize(e.0))
+}
+
+#[derive(Debug)]
+struct SerializeError(String);
+
+/// Serializes a struct into its values.
+struct StructSerializer<'a> {
+    options: &'a StructOptions,
+}
+
+struct StructFields<'a> {
+    options: &'a StructOptions,
+    values: Vec<Value>,
+}
+
+/// Serializes a single field into a column value.
+/// Sequences are only collected into bytes if the field is a blob.
+struct ColumnSerializer {
+    blob: bool,
+}
+
+/// Collects a sequence of `u8`, i.e. a `Vec<u8>` or `[u8; N]`, into bytes.
+struct ByteCollector {
+    bytes: Vec<u8>,
+}
+
+/// Serializes an element of a byte sequence, only `u8` is accepted.
+struct ByteSerializer;
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<T>(message: T) -> Self where T: fmt::Display {
+        SerializeError(message.to_string())
+    }
+}
+
+impl<'a> StructSerializer<'a> {
+    fn not_a_struct<T>() -> Result<T, SerializeError> {
+        Err(SerializeError(String::from("Only structs can be converted to values")))
+    }
+}
+
+impl<'a> Serializer for StructSerializer<'a> {
+    type Ok = Vec<Value>;
+    type Error = SerializeError;
+    type SerializeSeq = Impossible<Vec<Value>, SerializeError>;
+    type SerializeTuple = Impossible<Vec<Value>, SerializeError>;
+    type SerializeTupleStruct = Impossible<Vec<Value>, SerializeError>;
+    type SerializeTupleVariant = Impossible<Vec<Value>, SerializeError>;
+    type SerializeMap = Impossible<Vec<Value>, SerializeError>;
+    type SerializeStruct = StructFields<'a>;
+    type SerializeStructVariant = Impossible<Vec<Value>, SerializeError>;
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<StructFields<'a>, SerializeError> {
+        Ok(StructFields {
+            options: self.options,
+            values: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Vec<Value>, SerializeError> where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Vec<Value>, SerializeError> where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_i8(self, _v: i8) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_i16(self, _v: i16) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_i32(self, _v: i32) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_i64(self, _v: i64) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_u8(self, _v: u8) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_u16(self, _v: u16) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_u32(self, _v: u32) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_u64(self, _v: u64) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_f32(self, _v: f32) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_f64(self, _v: f64) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_char(self, _v: char) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_str(self, _v: &str) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_none(self) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_unit(self) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<Value>, SerializeError> { StructSerializer::not_a_struct() }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, _variant: &'static str) -> Result<Vec<Value>, SerializeError> {
+        StructSerializer::not_a_struct()
+    }
+
+    fn serialize_newtype_variant<T>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<Vec<Value>, SerializeError> where T: ?Sized + Serialize {
+        StructSerializer::not_a_struct()
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> { StructSerializer::not_a_struct() }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerializeError> { StructSerializer::not_a_struct() }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, SerializeError> {
+        StructSerializer::not_a_struct()
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        StructSerializer::not_a_struct()
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> { StructSerializer::not_a_struct() }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant, SerializeError> {
+        StructSerializer::not_a_struct()
+    }
+}
+
+impl<'a> SerializeStruct for StructFields<'a> {
+    type Ok = Vec<Value>;
+    type Error = SerializeError;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), SerializeError> where T: ?Sized + Serialize {
+        if self.options.skip.contains(key) {
+            return Ok(());
+        }
+
+        let column = match self.options.rename.get(key) {
+            Some(column) => column.clone(),
+            None => String::from(key)
+        };
+
+        let blob = self.options.blobs.contains(key);
+        let value = value.serialize(ColumnSerializer { blob }).map_err(|e| SerializeError(format!("Field `{}`: {}", key, e)))?;
+
+        let value = match (blob, value) {
+            (false, value) => Value::create(column, value),
+            (true, SqlValue::Null) => Value::create(column, SqlValue::Null),
+            (true, SqlValue::Blob(bytes)) => Value::create_blob(column, BlobRef::Memory(bytes)),
+            (true, SqlValue::Text(path)) => Value::create_blob(column, BlobRef::File(Box::from(Path::new(&path)))),
+            (true, _) => return Err(SerializeError(format!("Field `{}`: Blobs must be bytes or a file path", key)))
+        };
+
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Vec<Value>, SerializeError> {
+        Ok(self.values)
+    }
+}
+
+impl ColumnSerializer {
+    fn not_a_column<T>() -> Result<T, SerializeError> {
+        Err(SerializeError(String::from("Only plain values can be stored in a column")))
+    }
+
+    fn collect_bytes(&self, len: usize) -> Result<ByteCollector, SerializeError> {
+        match self.blob {
+            true => Ok(ByteCollector { bytes: Vec::with_capacity(len) }),
+            false => Err(SerializeError(String::from("Sequences can only be stored in a field marked with `StructOptions::blob`, use `serde_bytes` for bytes")))
+        }
+    }
+}
+
+impl Serializer for ColumnSerializer {
+    type Ok = SqlValue;
+    type Error = SerializeError;
+    type SerializeSeq = ByteCollector;
+    type SerializeTuple = ByteCollector;
+    type SerializeTupleStruct = Impossible<SqlValue, SerializeError>;
+    type SerializeTupleVariant = Impossible<SqlValue, SerializeError>;
+    type SerializeMap = Impossible<SqlValue, SerializeError>;
+    type SerializeStruct = Impossible<SqlValue, SerializeError>;
+    type SerializeStructVariant = Impossible<SqlValue, SerializeError>;
+
+    fn serialize_bool(self, v: bool) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_i8(self, v: i8) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_i16(self, v: i16) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_i32(self, v: i32) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_i64(self, v: i64) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v)) }
+    fn serialize_u8(self, v: u8) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_u16(self, v: u16) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+    fn serialize_u32(self, v: u32) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Integer(v as i64)) }
+
+    fn serialize_u64(self, v: u64) -> Result<SqlValue, SerializeError> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(SqlValue::Integer(v)),
+            Err(_) => Err(SerializeError(format!("{} is too large for an INTEGER column", v)))
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Real(v as f64)) }
+    fn serialize_f64(self, v: f64) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Real(v)) }
+    fn serialize_char(self, v: char) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Text(v.to_string())) }
+    fn serialize_str(self, v: &str) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Text(String::from(v))) }
+    fn serialize_bytes(self, v: &[u8]) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Blob(v.to_vec())) }
+    fn serialize_none(self) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Null) }
+    fn serialize_unit(self) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Null) }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<SqlValue, SerializeError> { Ok(SqlValue::Null) }
+
+    fn serialize_some<T>(self, value: &T) -> Result<SqlValue, SerializeError> where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    /// Stored as the variant's name, as `FromRow` reads it back.
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<SqlValue, SerializeError> {
+        Ok(SqlValue::Text(String::from(variant)))
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<SqlValue, SerializeError> where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<SqlValue, SerializeError> where T: ?Sized + Serialize {
+        ColumnSerializer::not_a_column()
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ByteCollector, SerializeError> {
+        self.collect_bytes(len.unwrap_or(0))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ByteCollector, SerializeError> {
+        self.collect_bytes(len)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, SerializeError> {
+        ColumnSerializer::not_a_column()
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        ColumnSerializer::not_a_column()
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> {
+        ColumnSerializer::not_a_column()
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, SerializeError> {
+        ColumnSerializer::not_a_column()
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant, SerializeError> {
+        ColumnSerializer::not_a_column()
+    }
+}
+
+impl ByteCollector {
+    fn push<T>(&mut self, value: &T) -> Result<(), SerializeError> where T: ?Sized + Serialize {
+        self.bytes.push(value.serialize(ByteSerializer)?);
+        Ok(())
+    }
+}
+
+impl SerializeSeq for ByteCollector {
+    type Ok = SqlValue;
+    type Error = SerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerializeError> where T: ?Sized + Serialize {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SqlValue, SerializeError> {
+        Ok(SqlValue::Blob(self.bytes))
+    }
+}
+
+impl SerializeTuple for ByteCollector {
+    type Ok = SqlValue;
+    type Error = SerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerializeError> where T: ?Sized + Serialize {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SqlValue, SerializeError> {
+        Ok(SqlValue::Blob(self.bytes))
+    }
+}
+
+impl ByteSerializer {
+    fn not_a_byte<T>() -> Result<T, SerializeError> {
+        Err(SerializeError(String::from("Only sequences of `u8` can be stored as a blob")))
+    }
+}
+
+impl Serializer for ByteSerializer {
+    type Ok = u8;
+    type Error = SerializeError;
+    type SerializeSeq = Impossible<u8, SerializeError>;
+    type SerializeTuple = Impossible<u8, SerializeError>;
+    type SerializeTupleStruct = Impossible<u8, SerializeError>;
+    type SerializeTupleVariant = Impossible<u8, SerializeError>;
+    type SerializeMap = Impossible<u8, SerializeError>;
+    type SerializeStruct = Impossible<u8, SerializeError>;
+    type SerializeStructVariant = Impossible<u8, SerializeError>;
+
+    fn serialize_u8(self, v: u8) -> Result<u8, SerializeError> { Ok(v) }
+
+    fn serialize_bool(self, _v: bool) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_i8(self, _v: i8) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_i16(self, _v: i16) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_i32(self, _v: i32) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_i64(self, _v: i64) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_u16(self, _v: u16) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_u32(self, _v: u32) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_u64(self, _v: u64) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_f32(self, _v: f32) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_f64(self, _v: f64) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_char(self, _v: char) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_str(self, _v: &str) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_none(self) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_unit(self) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<u8, SerializeError> { ByteSerializer::not_a_byte() }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<u8, SerializeError> where T: ?Sized + Serialize {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, _variant: &'static str) -> Result<u8, SerializeError> {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, _value: &T) -> Result<u8, SerializeError> where T: ?Sized + Serialize {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_newtype_variant<T>(self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T) -> Result<u8, SerializeError> where T: ?Sized + Serialize {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> { ByteSerializer::not_a_byte() }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerializeError> { ByteSerializer::not_a_byte() }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, SerializeError> {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> { ByteSerializer::not_a_byte() }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, SerializeError> {
+        ByteSerializer::not_a_byte()
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant, SerializeError> {
+        ByteSerializer::not_a_byte()
+    }
+}